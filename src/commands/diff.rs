@@ -0,0 +1,230 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use clap::ArgMatches;
+use cli_table::{Cell, Style, Table};
+use colored::Colorize;
+use rayon::prelude::*;
+use serde_json::json;
+
+use crate::commands::plan::{find_files, key_prefix, scan_file};
+use crate::{sql::EntriesRow, ClientConfig, Config, Error, Outcome, RetryPolicy, Sql, Target, S3};
+
+pub async fn diff(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    // buckets are compared before other targets, in the order they were given; each configured
+    // target is connected to with its own options
+    let mut targets: Vec<(Target, ClientConfig)> = matches
+        .get_many::<String>("bucket")
+        .into_iter()
+        .flatten()
+        .map(|bucket| {
+            (
+                Target::S3(bucket.clone()),
+                ClientConfig::for_target(matches, None),
+            )
+        })
+        .collect();
+    for value in matches.get_many::<String>("target").into_iter().flatten() {
+        let named = match value.contains("://") {
+            true => None,
+            false => Some(Config::get()?.target(value)?),
+        };
+        targets.push((
+            Target::resolve(value)?,
+            ClientConfig::for_target(matches, named),
+        ));
+    }
+    let dirs = matches
+        .get_many::<String>("dir")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let as_json = matches.get_flag("json");
+
+    let (a_label, a, b_label, b) =
+        match (targets.as_slice(), dirs.as_slice()) {
+            ([(a, a_client), (b, b_client)], []) => {
+                let (a_entries, a_hash_key) = bucket_entries(a, a_client, matches).await?;
+                let (b_entries, b_hash_key) = bucket_entries(b, b_client, matches).await?;
+                if a_hash_key != b_hash_key {
+                    return Err(Error::Config(format!(
+                    "{} and {} key their hashes differently, so their content can't be compared",
+                    a, b
+                ))
+                    .into());
+                }
+                (a.to_string(), a_entries, b.to_string(), b_entries)
+            }
+            ([(a, a_client)], [dir]) => {
+                let (a_entries, hash_key) = bucket_entries(a, a_client, matches).await?;
+                (
+                    a.to_string(),
+                    a_entries,
+                    dir.to_string(),
+                    local_entries(
+                        Path::new(dir),
+                        &key_prefix(matches, None),
+                        hash_key.as_ref(),
+                    )?,
+                )
+            }
+            // neither directory belongs to a target, so their hashes are not keyed
+            ([], [a, b]) => {
+                let prefix = key_prefix(matches, None);
+                (
+                    a.to_string(),
+                    local_entries(Path::new(a), &prefix, None)?,
+                    b.to_string(),
+                    local_entries(Path::new(b), &prefix, None)?,
+                )
+            }
+            _ => return Err(Error::Config(
+                "diff takes two buckets or targets, one bucket or target and --dir, or two --dir"
+                    .into(),
+            )
+            .into()),
+        };
+
+    let a_keys = a
+        .iter()
+        .map(|e| (e.key.as_str(), e))
+        .collect::<HashMap<_, _>>();
+    let b_keys = b
+        .iter()
+        .map(|e| (e.key.as_str(), e))
+        .collect::<HashMap<_, _>>();
+    let a_hashes = a.iter().map(|e| e.hash.as_str()).collect::<HashSet<_>>();
+    let b_hashes = b.iter().map(|e| e.hash.as_str()).collect::<HashSet<_>>();
+
+    let mut only_in_a: Vec<&str> = Vec::new();
+    let mut different_content: Vec<(&str, &str, &str)> = Vec::new();
+    let mut same_content: BTreeSet<(&str, &str)> = BTreeSet::new();
+    let mut num_identical = 0;
+    for entry in &a {
+        match b_keys.get(entry.key.as_str()) {
+            Some(other) if other.hash == entry.hash => num_identical += 1,
+            Some(other) => different_content.push((&entry.key, &entry.hash, &other.hash)),
+            None if b_hashes.contains(entry.hash.as_str()) => {
+                for other in b.iter().filter(|e| e.hash == entry.hash) {
+                    same_content.insert((&entry.key, &other.key));
+                }
+            }
+            None => only_in_a.push(&entry.key),
+        }
+    }
+    let mut only_in_b: Vec<&str> = Vec::new();
+    for entry in b.iter().filter(|e| !a_keys.contains_key(e.key.as_str())) {
+        if a_hashes.contains(entry.hash.as_str()) {
+            for other in a.iter().filter(|e| e.hash == entry.hash) {
+                same_content.insert((&other.key, &entry.key));
+            }
+        } else {
+            only_in_b.push(&entry.key);
+        }
+    }
+    only_in_a.sort();
+    only_in_b.sort();
+    different_content.sort();
+
+    let num_differences =
+        only_in_a.len() + only_in_b.len() + different_content.len() + same_content.len();
+
+    if as_json {
+        let output = json!({
+            "a": a_label,
+            "b": b_label,
+            "only_in_a": only_in_a,
+            "only_in_b": only_in_b,
+            "different_content": different_content
+                .iter()
+                .map(|(key, a_hash, b_hash)| json!({ "key": key, "a_hash": a_hash, "b_hash": b_hash }))
+                .collect::<Vec<_>>(),
+            "same_content": same_content
+                .iter()
+                .map(|(a_key, b_key)| json!({ "a_key": a_key, "b_key": b_key }))
+                .collect::<Vec<_>>(),
+            "identical": num_identical,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else if num_differences > 0 {
+        let mut rows = Vec::with_capacity(num_differences);
+        for key in &only_in_a {
+            rows.push(vec!["only in A".cell(), key.cell(), "".cell()]);
+        }
+        for key in &only_in_b {
+            rows.push(vec!["only in B".cell(), "".cell(), key.cell()]);
+        }
+        for (key, _, _) in &different_content {
+            rows.push(vec!["different content".cell(), key.cell(), key.cell()]);
+        }
+        for (a_key, b_key) in &same_content {
+            rows.push(vec!["same content".cell(), a_key.cell(), b_key.cell()]);
+        }
+        let table = rows.table().title(vec![
+            "Difference".cell().bold(true),
+            format!("A: {}", a_label).cell().bold(true),
+            format!("B: {}", b_label).cell().bold(true),
+        ]);
+        println!("{}", table.display()?);
+    }
+
+    if !as_json {
+        println!(
+            "{}",
+            format!(
+                "{} only in A, {} only in B, {} with different content, {} with same content at a different key, {} identical",
+                only_in_a.len(),
+                only_in_b.len(),
+                different_content.len(),
+                same_content.len(),
+                num_identical
+            )
+            .white()
+        );
+    }
+
+    if num_differences > 0 {
+        Ok(Outcome::Differences)
+    } else {
+        Ok(Outcome::Done)
+    }
+}
+
+/// Reads the entries in a target's database, which is empty if the target has none, along with
+/// the key its hashes are keyed with, if any.
+async fn bucket_entries(
+    target: &Target,
+    client: &ClientConfig,
+    matches: &ArgMatches,
+) -> anyhow::Result<(Vec<EntriesRow>, Option<[u8; 32]>)> {
+    let s3 = S3::new(target, client, RetryPolicy::from_matches(matches)).await?;
+    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
+    if exists {
+        s3.get("_s3b_db/").await?;
+    }
+
+    let mut sql = Sql::new().await?;
+    let entries = sql.get_entries().await?;
+    std::fs::remove_dir_all("_s3b_db")?;
+    Ok((entries, s3.hash_key().copied()))
+}
+
+/// Hashes the files under a local directory into entries, keyed and hashed as `plan` would from
+/// there.
+fn local_entries(
+    dir: &Path,
+    prefix: &str,
+    hash_key: Option<&[u8; 32]>,
+) -> anyhow::Result<Vec<EntriesRow>> {
+    let base_path = PathBuf::from(dir)
+        .canonicalize()
+        .map_err(|err| Error::NotFound(format!("could not resolve {:?}: {}", dir, err)))?;
+    let base_path_str = base_path
+        .to_str()
+        .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &base_path))?;
+    find_files(&base_path, &[], &[])?
+        .into_par_iter()
+        .map(|path| EntriesRow::new(&scan_file(path, base_path_str, prefix, hash_key)?))
+        .collect()
+}
@@ -2,30 +2,77 @@ use std::path::Path;
 
 use clap::ArgMatches;
 
-use crate::{Sql, S3};
+use crate::{pack, sql::quote, ClientConfig, Outcome, RetryPolicy, Sql, Target, S3};
 
-pub async fn drop(matches: &ArgMatches) -> anyhow::Result<()> {
-    let bucket_name = matches.get_one::<String>("bucket").unwrap();
+pub async fn drop(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
     let path = matches.get_one::<String>("path").unwrap();
-    let endpoint = matches.get_one::<String>("endpoint");
 
     // TODO check for lock
     //      lock should be its own operation, i.e. s3b lock & s3b lock --release
-    let s3 = S3::new(&bucket_name, endpoint.map(|s| s.as_str())).await?;
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
     let exists = s3.key_exists("_s3b_db/entries.sql").await?;
     if exists {
         s3.get("_s3b_db/").await?;
     }
 
-    if let Ok(deleted) = s3.delete(&path).await {
-        let mut sql = Sql::new().await?;
-        for key in deleted {
-            sql.delete_entry_by_key(&key).await?;
+    let mut sql = Sql::new().await?;
+    let selected = match path.ends_with('/') {
+        true => format!("key LIKE {}", quote(&format!("{}%", path))),
+        false => format!("key={}", quote(path)),
+    };
+    // packed entries have no object of their own; their packs are deleted once nothing refers to them
+    let packed = sql
+        .get_entries_where(&format!("pack_key IS NOT NULL AND {}", selected))
+        .await?;
+    let mut deleted = if packed.iter().any(|entry| &entry.key == path) {
+        Vec::new()
+    } else if s3.is_encrypted() && path.ends_with('/') {
+        // the objects of an encrypted target are stored under hidden names, so the keys under
+        // the path are taken from the database
+        let entries = sql
+            .get_entries_where(&format!("pack_key IS NULL AND {}", selected))
+            .await?;
+        let mut deleted = Vec::with_capacity(entries.len());
+        for entry in entries {
+            deleted.extend(s3.delete(&entry.key).await?);
         }
+        deleted
+    } else if path.ends_with('/') || s3.key_exists(path).await? {
+        s3.delete(path).await?
+    } else {
+        // an entry whose object is already gone is still dropped from the database
+        sql.get_entries_where(&selected)
+            .await?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect()
+    };
+    deleted.extend(packed.iter().map(|entry| entry.key.clone()));
+    for key in &deleted {
+        sql.delete_entry_by_key(key).await?;
     }
+    pack::delete_unreferenced(
+        &s3,
+        &mut sql,
+        packed.iter().filter_map(|entry| entry.pack_key.as_deref()),
+    )
+    .await?;
 
-    s3.put(Path::new("_s3b_db/")).await?;
-    std::fs::remove_dir_all("_s3b_db").unwrap();
+    // rewriting the database changes its ETag, which would make every pending plan stale
+    if !deleted.is_empty() {
+        s3.put(Path::new("_s3b_db/")).await?;
+    }
+    std::fs::remove_dir_all("_s3b_db")?;
 
-    Ok(())
+    if deleted.is_empty() {
+        Ok(Outcome::NothingToDo)
+    } else {
+        Ok(Outcome::Done)
+    }
 }
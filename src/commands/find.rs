@@ -1,27 +1,43 @@
 use chrono::{prelude::DateTime, Utc};
-use std::path::Path;
 use std::time::{Duration, UNIX_EPOCH};
 
 use clap::ArgMatches;
 use cli_table::{Cell, Style, Table};
 
-use crate::{Sql, S3};
+use crate::{
+    sql::{quote, EntriesRow},
+    ClientConfig, Outcome, RetryPolicy, Sql, Target, S3,
+};
 
-pub async fn find(matches: &ArgMatches) -> anyhow::Result<()> {
-    let bucket_name = matches.get_one::<String>("bucket").unwrap();
-    let endpoint = matches.get_one::<String>("endpoint");
-    let wherestr = matches.get_one::<String>("where").unwrap();
+pub async fn find(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
+    let mut conditions: Vec<String> = Vec::new();
+    if let Some(w) = matches.get_one::<String>("where") {
+        conditions.push(format!("({})", w));
+    }
+    if let Some(source) = matches.get_one::<String>("source") {
+        conditions.push(format!("source={}", quote(source)));
+    }
+    if let Some(class) = matches.get_one::<String>("storage-class") {
+        conditions.push(format!("storage_class={}", quote(class)));
+    }
+    let wherestr = conditions.join(" AND ");
 
     // TODO check for lock
     //      lock should be its own operation, i.e. s3b lock & s3b lock --release
-    let s3 = S3::new(&bucket_name, endpoint.map(|s| s.as_str())).await?;
-    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
-    if exists {
-        s3.get("_s3b_db/").await?;
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
+    let remote_entries = query(&s3, &wherestr).await;
+    // the database is only read, so the local copy is removed whether or not the query succeeded
+    match std::fs::remove_dir_all("_s3b_db") {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
     }
-
-    let mut sql = Sql::new().await?;
-    let remote_entries = sql.get_entries_where(&wherestr).await?;
+    let remote_entries = remote_entries?;
     let table = remote_entries
         .iter()
         .map(|entry| {
@@ -34,6 +50,10 @@ pub async fn find(matches: &ArgMatches) -> anyhow::Result<()> {
                 entry.hash.clone().cell(),
                 entry.path.clone().cell(),
                 modified_time.cell(),
+                entry.source.clone().unwrap_or_default().cell(),
+                entry.storage_class.clone().unwrap_or_default().cell(),
+                entry.codec.clone().unwrap_or_default().cell(),
+                entry.pack_key.clone().unwrap_or_default().cell(),
             ]
         })
         .collect::<Vec<_>>()
@@ -43,12 +63,23 @@ pub async fn find(matches: &ArgMatches) -> anyhow::Result<()> {
             "Hash".cell().bold(true),
             "Origin Path".cell().bold(true),
             "Modified Time (UTC)".cell().bold(true),
+            "Source".cell().bold(true),
+            "Storage Class".cell().bold(true),
+            "Codec".cell().bold(true),
+            "Pack".cell().bold(true),
         ]);
 
-    println!("{}", table.display().unwrap());
-	
-    s3.put(Path::new("_s3b_db/")).await?;
-    std::fs::remove_dir_all("_s3b_db").unwrap();
-	
-    Ok(())
+    println!("{}", table.display()?);
+
+    Ok(Outcome::Done)
+}
+
+/// Downloads the database and selects the entries matching `wherestr`.
+async fn query(s3: &S3, wherestr: &str) -> anyhow::Result<Vec<EntriesRow>> {
+    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
+    if exists {
+        s3.get("_s3b_db/").await?;
+    }
+    let mut sql = Sql::new().await?;
+    sql.get_entries_where(wherestr).await
 }
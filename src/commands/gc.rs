@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use chrono::{Duration, Utc};
+
+use clap::ArgMatches;
+use cli_table::{Cell, Style, Table};
+use colored::Colorize;
+
+use crate::commands::plan::key_prefix;
+use crate::{uploads::UploadLog, ClientConfig, Config, Error, Outcome, RetryPolicy, Target, S3};
+
+pub async fn gc(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    match matches.subcommand() {
+        Some(("multipart", subcommand)) => gc_multipart(subcommand).await,
+        _ => unreachable!("skipper's drunk!"),
+    }
+}
+
+async fn gc_multipart(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
+    let older_than = *matches.get_one::<u64>("older-than").unwrap();
+    let all = matches.get_flag("all");
+
+    // other hosts and tools may be uploading to the same bucket, so only uploads under this
+    // backup's prefix are aborted unless every upload is asked for
+    let prefix = key_prefix(matches, Config::selected_target(matches)?);
+    if !all && prefix.is_empty() {
+        return Err(Error::Config(
+            "gc multipart would abort the uploads of every client of the bucket; pass --prefix to limit it to this backup's keys, or --all".into(),
+        )
+        .into());
+    }
+
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
+    if !all && s3.is_encrypted() {
+        return Err(Error::Config(format!(
+            "objects in {} are stored under hidden names, so uploads can't be told apart by prefix; pass --all",
+            target
+        ))
+        .into());
+    }
+
+    // uploads which pushing the plan again can still resume are left alone
+    let log = UploadLog::read(Path::new(matches.get_one::<String>("plan").unwrap()))?;
+    let cutoff = Utc::now() - Duration::hours(older_than as i64);
+    let orphaned = s3
+        .list_multipart_uploads()
+        .await?
+        .into_iter()
+        .filter(|upload| all || upload.key.starts_with(&prefix))
+        .filter(|upload| !log.contains_upload_id(&target, &upload.upload_id))
+        .filter(|upload| upload.initiated.is_none_or(|t| t < cutoff))
+        .collect::<Vec<_>>();
+
+    if orphaned.is_empty() {
+        println!("{}", "No orphaned multipart uploads found.".white());
+        return Ok(Outcome::NothingToDo);
+    }
+
+    for upload in &orphaned {
+        s3.abort_multipart(&upload.key, &upload.upload_id).await?;
+    }
+
+    let table = orphaned
+        .iter()
+        .map(|upload| {
+            vec![
+                upload.key.clone().cell(),
+                upload.upload_id.clone().cell(),
+                upload
+                    .initiated
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default()
+                    .cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "Key".cell().bold(true),
+            "Upload ID".cell().bold(true),
+            "Initiated Time (UTC)".cell().bold(true),
+        ]);
+    println!("{}", table.display()?);
+    println!(
+        "{}",
+        format!("Aborted {} multipart uploads.", orphaned.len()).green()
+    );
+
+    Ok(Outcome::Done)
+}
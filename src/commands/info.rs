@@ -4,16 +4,20 @@ use std::time::{Duration, UNIX_EPOCH};
 use clap::ArgMatches;
 use cli_table::{Cell, Style, Table};
 
-use crate::{Sql, S3};
+use crate::{ClientConfig, Error, Outcome, RetryPolicy, Sql, Target, S3};
 
-pub async fn info(matches: &ArgMatches) -> anyhow::Result<()> {
-    let bucket_name = matches.get_one::<String>("bucket").unwrap();
-    let endpoint = matches.get_one::<String>("endpoint");
+pub async fn info(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
     let key = matches.get_one::<String>("key").unwrap();
 
     // TODO check for lock
     //      lock should be its own operation, i.e. s3b lock & s3b lock --release
-    let s3 = S3::new(&bucket_name, endpoint.map(|s| s.as_str())).await?;
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
     let exists = s3.key_exists("_s3b_db/entries.sql").await?;
     if exists {
         s3.get("_s3b_db/").await?;
@@ -21,6 +25,7 @@ pub async fn info(matches: &ArgMatches) -> anyhow::Result<()> {
 
     let mut sql = Sql::new().await?;
     let remote_entries = sql.get_entries().await?;
+    std::fs::remove_dir_all("_s3b_db")?;
 
     match remote_entries.iter().find(|&e| e.key == *key) {
         Some(entry) => {
@@ -41,10 +46,11 @@ pub async fn info(matches: &ArgMatches) -> anyhow::Result<()> {
                 "Origin Path".cell().bold(true),
                 "Modified Time (UTC)".cell().bold(true),
             ]);
-            println!("{}", table.display().unwrap());
+            println!("{}", table.display()?);
+            Ok(Outcome::Done)
         }
-        None => println!("No remote object with key '{}' was found.", key),
-    };
-
-    Ok(())
+        None => {
+            Err(Error::NotFound(format!("no remote object with key '{}' was found", key)).into())
+        }
+    }
 }
@@ -1,5 +1,11 @@
+pub mod diff;
 pub mod drop;
 pub mod find;
+pub mod gc;
 pub mod info;
 pub mod plan;
 pub mod push;
+pub mod restore;
+pub mod show_plan;
+pub mod status;
+pub mod verify;
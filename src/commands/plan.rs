@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Mutex,
@@ -11,74 +11,67 @@ use clap::ArgMatches;
 use colored::Colorize;
 use itertools::Itertools;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
-use crate::{sql::EntriesRow, Plan, PlanEntry, Sql, S3};
-
-pub async fn plan(matches: &ArgMatches) -> anyhow::Result<()> {
-    let bucket_name = matches.get_one::<String>("bucket").unwrap();
-    let endpoint = matches.get_one::<String>("endpoint");
+use crate::{
+    compress::{self, Codec},
+    config::TargetConfig,
+    pack::{MAX_PACK_THRESHOLD, PACK_PREFIX, PACK_SIZE},
+    parse_size,
+    s3::check_storage_class,
+    sql::EntriesRow,
+    ClientConfig, Config, Error, Outcome, Plan, PlanEncoding, PlanEntry, RetryPolicy, Sql, Target,
+    S3,
+};
 
-    let exclude: Vec<&String> = match matches.get_many("exclude") {
-        Some(m) => m.collect(),
-        None => Vec::new(),
-    };
+pub async fn plan(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
+    let target_name = Config::target_name(matches)?;
 
-    let include: Vec<&String> = match matches.get_many("include") {
-        Some(m) => m.collect(),
-        None => Vec::new(),
+    let prefix = key_prefix(matches, Config::selected_target(matches)?);
+    let source = match matches.get_one::<String>("source") {
+        Some(source) => Some(source.clone()),
+        None => Config::selected_set(matches)?.and_then(|set| set.source.clone()),
     };
 
-    let spinner = indicatif::ProgressBar::new_spinner();
-    spinner.enable_steady_tick(Duration::from_millis(120));
-    spinner.set_message("Finding files...");
-    let mut filtered_entries: Vec<PathBuf> = Vec::new();
-    for entry in WalkDir::new("./").min_depth(1) {
-        let entry = entry.unwrap();
-        let entry = match entry.path().canonicalize() {
-            Ok(entry) => entry,
-            Err(_) => return Err(anyhow!("could not resolve {:?}; is this a symlink which no longer exists?", entry.path())),
-        };
-        if entry.is_file() && !entry.is_symlink() {
-            if include.len() > 0 {
-                if filter(include.clone(), entry.clone()) {
-                    filtered_entries.push(entry);
-                }
-            } else {
-                filtered_entries.push(entry);
-            }
-        }
+    let selection = Selection::from_matches(matches)?;
+    let storage_classes = StorageClasses::from_matches(matches)?;
+    let compression = Compression::from_matches(matches)?;
+    let packing = Packing::from_matches(matches)?;
+    if !storage_classes.is_empty() && !matches!(target, Target::S3(_)) {
+        return Err(Error::Config(format!(
+            "storage classes are only supported for S3 targets, not {}",
+            target
+        ))
+        .into());
     }
-    let filtered_entries: Vec<PathBuf> = filtered_entries
-        .into_iter()
-        .filter(|path| !filter(exclude.clone(), path.clone()))
-        .collect();
-    let filtered_entries: Vec<PathBuf> = filtered_entries
+    let plan_out = Path::new(matches.get_one::<String>("plan-out").unwrap());
+    let plan_out_path = plan_out.canonicalize().ok();
+    let filtered_entries = find_files(&selection.root, &selection.include(), &selection.exclude())?
         .into_iter()
-        .unique()
-        .filter(|path| {
-            path.components()
-                .find(|&c| c.as_os_str().to_str().unwrap() == "_s3b_db")
-                .is_none()
-                && path.file_name().unwrap() != "s3b_plan.bin"
-        })
-        .collect();
-    spinner.finish_with_message(format!("Found {} entries", filtered_entries.len()));
+        .filter(|path| Some(path) != plan_out_path.as_ref())
+        .collect::<Vec<_>>();
 
     // TODO check for lock
     //      lock should be its own operation, i.e. s3b lock & s3b lock --release
-    let s3 = S3::new(&bucket_name, endpoint.map(|s| s.as_str())).await?;
+    let client = ClientConfig::from_matches(matches)?;
+    let s3 = S3::new(&target, &client, RetryPolicy::from_matches(matches)).await?;
     let exists = s3.key_exists("_s3b_db/entries.sql").await?;
     if exists {
         s3.get("_s3b_db/").await?;
     }
+    let db_etag = s3.e_tag("_s3b_db/entries.jsonl").await?;
 
     let mut sql = Sql::new().await?;
     let remote_entries = sql.get_entries().await?;
     // println!("remote={:?}", remote_entries);
 
     println!("Processing entries...");
-    let base_path = PathBuf::from("./").canonicalize().unwrap();
+    let base_path = selection.root;
+    let base_path_str = base_path
+        .to_str()
+        .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &base_path))?;
     let warnings: Mutex<Vec<String>> = Mutex::new(Vec::new());
     let prompt_list: Mutex<Vec<String>> = Mutex::new(Vec::new());
     let prompt_entries: Mutex<Vec<PlanEntry>> = Mutex::new(Vec::new());
@@ -87,140 +80,90 @@ pub async fn plan(matches: &ArgMatches) -> anyhow::Result<()> {
     let pb = indicatif::ProgressBar::new(filtered_entries.len() as u64);
     let num_skipped: AtomicU64 = AtomicU64::new(0);
     let num_new: AtomicU64 = AtomicU64::new(0);
-    filtered_entries.into_par_iter().for_each(|path| {
-        let metadata = match std::fs::metadata(&path) {
-            Ok(m) => m,
-            Err(_err) => panic!("could not stat {:?}", &path),
-        };
-        let modified = metadata.modified().unwrap();
-        let dt = DateTime::<Local>::from(
-            UNIX_EPOCH
-                + Duration::from_secs(
-                    modified
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
-                ),
-        );
-        let dt_utc = DateTime::<Utc>::from(dt);
-        let timestamp = dt_utc.timestamp() as u64;
-
-        let contents = std::fs::read(&path).unwrap();
-        let hash = blake3::hash(&contents).to_string();
+    filtered_entries
+        .into_par_iter()
+        .try_for_each(|path| -> anyhow::Result<()> {
+            let mut plan_entry = scan_file(path, base_path_str, &prefix, s3.hash_key())?;
+            plan_entry.storage_class = storage_classes.for_path(&plan_entry.path);
+            plan_entry.codec = compression
+                .for_path(&plan_entry.path)
+                .map(|codec| codec.name().to_string());
+            let this_key = plan_entry.key.clone();
 
-        let key = path
-            .to_str()
-            .unwrap()
-            .replace(&format!("{}/", base_path.to_str().unwrap()), "");
-
-        let plan_entry = PlanEntry {
-            key: key.clone(),
-            path,
-            hash: hash.clone(),
-            modified: timestamp,
-        };
-
-        let mut modified_key: Option<&EntriesRow> = None;
-        let mut existing_hashes: Vec<&EntriesRow> = Vec::new();
-        let this_hash = hash.clone();
-        let this_key = key.clone();
-        remote_entries.iter().for_each(|remote| {
-            if remote.hash == this_hash {
-                existing_hashes.push(remote);
-            }
-            if remote.key == this_key && remote.hash != this_hash {
-                modified_key = Some(remote);
-            }
-        });
-        let mut skip = false;
-        let mut prompt = false;
-        if let Some(remote) = modified_key {
-            // different hash at same key, prompt
-            let local_modified_time = modified
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if remote.modified > local_modified_time {
-                // println!("Key {} exists, remote is newer", &remote.key);
-                prompt_list
-                    .lock()
-                    .unwrap()
-                    .push(format!("{} [remote is newer]", &remote.key));
-                prompt_entries.lock().unwrap().push(plan_entry.clone());
-            } else if remote.modified < local_modified_time {
-                // println!("Key {} exists, remote is older", &remote.key);
-                prompt_list
-                    .lock()
-                    .unwrap()
-                    .push(format!("{} [remote is older]", &remote.key));
-                prompt_entries.lock().unwrap().push(plan_entry.clone());
-            } else {
-                panic!(
-                    "Key {} exists with different hash but timestamp is same; this may be a bug",
-                    &remote.key
-                );
+            let (change, existing_hashes) = compare(&plan_entry, &remote_entries);
+            plan_entry.change = change;
+            let mut skip = false;
+            match change {
+                Change::New => {
+                    num_new.fetch_add(1, Ordering::Relaxed);
+                }
+                Change::Identical => {
+                    // key exists with same hash, skip
+                    num_skipped.fetch_add(1, Ordering::Relaxed);
+                    skip = true;
+                }
+                Change::Modified | Change::RemoteNewer => {
+                    // different hash at same key, prompt
+                    prompt_list.lock().unwrap().push(format!(
+                        "{} [{}]",
+                        &this_key,
+                        change.conflict_reason().unwrap()
+                    ));
+                    prompt_entries.lock().unwrap().push(plan_entry.clone());
+                    skip = true;
+                }
+                Change::Conflict => {
+                    return Err(Error::Conflict(format!(
+                    "key {} exists with different hash but timestamp is same; this may be a bug",
+                    &this_key
+                ))
+                    .into());
+                }
             }
-            prompt = true;
-            skip = true;
-        }
-        if existing_hashes
-            .iter()
-            .find(|&remote| remote.key == this_key)
-            .is_some()
-        {
-            // key exists with same hash, skip
-            // println!("Key {} exists with same hash", &this_key);
-            num_skipped.fetch_add(1, Ordering::Relaxed);
-            skip = true;
-        }
 
-        if !skip && !prompt {
-            num_new.fetch_add(1, Ordering::Relaxed);
-        }
+            if !skip && !existing_hashes.is_empty() {
+                // not skipped but identical hashes found, flag
+                let mut warning = format!(
+                    "Identical hashes found for new object {} at keys:\n",
+                    &this_key.bold().white()
+                );
 
-        if !skip && existing_hashes.len() > 0 {
-            // not skipped but identical hashes found, flag
-            let mut warning = format!(
-                "Identical hashes found for new object {} at keys:\n",
-                &this_key.bold().white()
-            );
+                for i in existing_hashes.iter().map(|e| &e.key).collect::<Vec<_>>() {
+                    warning.push_str(&format!("    - {}\n", i.bold().white()));
+                }
+                warning.push('\n');
+                warnings.lock().unwrap().push(warning);
+            }
+            // TODO this is potentially an overwhelming number of warnings, which also needs to be deduped between entries
+            //      could instead make this a separate command operating on the remote store
+            // if skip && !prompt && existing_hashes.len() > 1 {
+            //     // skipped but there are multiple identical hashes, flag
+            //     let mut warning = format!(
+            //         "Key {} skipped but remote objects with identical hashes found: {:?}",
+            //         &this_key,
+            //         existing_hashes
+            //             .iter()
+            //             .filter(|e| e.key != this_key)
+            //             .collect::<Vec<_>>()
+            //     );
+            //     warning.push('\n');
+            //     warnings.lock().unwrap().push(warning);
+            // }
 
-            for i in existing_hashes.iter().map(|e| &e.key).collect::<Vec<_>>() {
-                warning.push_str(&format!("    - {}\n", i.bold().white()));
+            if !skip {
+                planned_entries.lock().unwrap().push(plan_entry);
             }
-            warning.push('\n');
-            warnings.lock().unwrap().push(warning);
-        }
-        // TODO this is potentially an overwhelming number of warnings, which also needs to be deduped between entries
-        //      could instead make this a separate command operating on the remote store
-        // if skip && !prompt && existing_hashes.len() > 1 {
-        //     // skipped but there are multiple identical hashes, flag
-        //     let mut warning = format!(
-        //         "Key {} skipped but remote objects with identical hashes found: {:?}",
-        //         &this_key,
-        //         existing_hashes
-        //             .iter()
-        //             .filter(|e| e.key != this_key)
-        //             .collect::<Vec<_>>()
-        //     );
-        //     warning.push('\n');
-        //     warnings.lock().unwrap().push(warning);
-        // }
-
-        if !skip {
-            planned_entries.lock().unwrap().push(plan_entry);
-        }
 
-        pb.inc(1);
-    });
+            pb.inc(1);
+            Ok(())
+        })?;
     pb.finish();
     let mut entries = planned_entries.into_inner().unwrap();
 
     let prompt_list = prompt_list.into_inner().unwrap();
-    if prompt_list.len() > 0 {
+    if !prompt_list.is_empty() {
         let ans = inquire::MultiSelect::new("Select conflicting objects to include", prompt_list)
-            .prompt()
-            .unwrap();
+            .prompt()?;
         let selected_keys = ans
             .iter()
             .map(|i| i.split(" [").collect::<Vec<_>>()[0])
@@ -229,7 +172,7 @@ pub async fn plan(matches: &ArgMatches) -> anyhow::Result<()> {
             .into_inner()
             .unwrap()
             .into_iter()
-            .filter(|e| selected_keys.iter().find(|&s| *s == e.key).is_some())
+            .filter(|e| selected_keys.iter().any(|&s| s == e.key))
             .collect::<Vec<_>>();
         entries.append(&mut prompt_entries);
         // println!("Selected {:?}", selected_keys);
@@ -240,11 +183,13 @@ pub async fn plan(matches: &ArgMatches) -> anyhow::Result<()> {
         println!(" - {}", warning);
     }
 
+    let num_packs = packing.assign(&mut entries);
+
     let num_entries = entries.len() as u64;
     let num_new = num_new.load(Ordering::Relaxed);
     let num_skipped = num_skipped.load(Ordering::Relaxed);
     let num_updated = num_entries - num_new;
-    if entries.len() > 0 {
+    if !entries.is_empty() {
         println!(
             "\n{}",
             format!(
@@ -253,27 +198,421 @@ pub async fn plan(matches: &ArgMatches) -> anyhow::Result<()> {
             )
             .green()
         );
+        if num_packs > 0 {
+            println!(
+                "{}",
+                format!(
+                    "Small files will be packed into {} pack objects.",
+                    num_packs
+                )
+                .green()
+            );
+        }
     } else {
         println!("\n{}", "Plan is empty; nothing new to upload.".white());
     }
 
+    let encoding = match matches.get_one::<String>("plan-format").map(|s| s.as_str()) {
+        Some("json") => PlanEncoding::Json,
+        _ => PlanEncoding::Binary,
+    };
     let plan = Plan {
-        bucket_name: bucket_name.to_string(),
-        base_path,
-        entries,
+        target_name,
+        prefix,
+        source,
+        ..Plan::new(
+            &target,
+            base_path,
+            entries,
+            db_etag,
+            client.endpoint.as_deref(),
+            encoding,
+        )
     };
     // println!("{:?}", plan);
-    plan.write();
-    std::fs::remove_dir_all("_s3b_db").unwrap();
+    plan.write(plan_out)?;
+    std::fs::remove_dir_all("_s3b_db")?;
+
+    if plan.entries.is_empty() {
+        Ok(Outcome::NothingToDo)
+    } else {
+        Ok(Outcome::Done)
+    }
+}
 
-    Ok(())
+/// How a local file compares to the remote entry at the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    /// No remote entry exists at the key
+    New,
+    Identical,
+    /// The remote entry has a different hash and is older than the local file
+    Modified,
+    /// The remote entry has a different hash and is newer than the local file
+    RemoteNewer,
+    /// The remote entry has a different hash but the same modified time
+    Conflict,
 }
 
-fn filter(list: Vec<&String>, entry: PathBuf) -> bool {
-    for l in list {
-        if entry.to_str().unwrap().contains(l.as_str()) {
-            return true;
+impl Change {
+    /// The action `push` takes for an entry with this change.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Change::New => "new",
+            Change::Identical => "skip",
+            _ => "update",
         }
     }
-    return false;
+
+    /// Why the entry conflicts with the object at the same key, if it does.
+    pub fn conflict_reason(&self) -> Option<&'static str> {
+        match self {
+            Change::Modified => Some("remote is older"),
+            Change::RemoteNewer => Some("remote is newer"),
+            Change::Conflict => Some("same modified time"),
+            _ => None,
+        }
+    }
+}
+
+/// Walks `root` for files, applying the `--include` and `--exclude` filters and
+/// skipping s3b's own state files.
+pub fn find_files(
+    root: &Path,
+    include: &[&String],
+    exclude: &[&String],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let spinner = indicatif::ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(120));
+    spinner.set_message("Finding files...");
+    let mut filtered_entries: Vec<PathBuf> = Vec::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry?;
+        let entry = match entry.path().canonicalize() {
+            Ok(entry) => entry,
+            Err(_) => {
+                return Err(anyhow!(
+                    "could not resolve {:?}; is this a symlink which no longer exists?",
+                    entry.path()
+                ))
+            }
+        };
+        if entry.is_file() && !entry.is_symlink() && is_selected(include, exclude, &entry) {
+            filtered_entries.push(entry);
+        }
+    }
+    let filtered_entries: Vec<PathBuf> = filtered_entries
+        .into_iter()
+        .unique()
+        .filter(|path| {
+            !path.components().any(|c| c.as_os_str() == "_s3b_db")
+                && path.file_name().is_some_and(|name| {
+                    name != "s3b_plan.bin" && name != "s3b_uploads.bin" && name != "s3b_restore.bin"
+                })
+        })
+        .collect();
+    spinner.finish_with_message(format!("Found {} entries", filtered_entries.len()));
+    Ok(filtered_entries)
+}
+
+/// Returns whether a path passes the `--include` and `--exclude` filters.
+pub fn is_selected(include: &[&String], exclude: &[&String], path: &Path) -> bool {
+    (include.is_empty() || filter(include, path)) && !filter(exclude, path)
+}
+
+/// The files a command looks at: those under the root of its backup set, or else under the
+/// current directory, filtered by `--include` and `--exclude` or else by the set's filters.
+pub struct Selection {
+    /// The canonical path of the directory to walk, which keys are relative to
+    pub root: PathBuf,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Selection {
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let set = Config::selected_set(matches)?;
+        let filters = |arg: &str, from_set: Option<&Vec<String>>| -> Vec<String> {
+            match matches.get_many::<String>(arg) {
+                Some(m) => m.cloned().collect(),
+                None => from_set.cloned().unwrap_or_default(),
+            }
+        };
+        let root = set.map_or_else(|| PathBuf::from("./"), |set| set.root.clone());
+        Ok(Selection {
+            root: root.canonicalize().map_err(|err| {
+                Error::NotFound(format!("could not resolve {}: {}", root.display(), err))
+            })?,
+            include: filters("include", set.map(|set| &set.include)),
+            exclude: filters("exclude", set.map(|set| &set.exclude)),
+        })
+    }
+
+    pub fn include(&self) -> Vec<&String> {
+        self.include.iter().collect()
+    }
+
+    pub fn exclude(&self) -> Vec<&String> {
+        self.exclude.iter().collect()
+    }
+}
+
+/// Chooses the storage class of each planned file: that of the first `PATTERN=CLASS` rule whose
+/// pattern the path contains, or else the class given without a pattern, or else the class of the
+/// configured target. Rules are taken from `--storage-class`, or else from the backup set.
+pub struct StorageClasses {
+    rules: Vec<(String, String)>,
+    default: Option<String>,
+}
+
+impl StorageClasses {
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let values: Vec<String> = match matches.get_many::<String>("storage-class") {
+            Some(m) => m.cloned().collect(),
+            None => Config::selected_set(matches)?
+                .map(|set| set.storage_class.clone())
+                .unwrap_or_default(),
+        };
+        let mut rules = Vec::new();
+        let mut default = None;
+        for value in values {
+            match value.rsplit_once('=') {
+                Some((pattern, class)) => {
+                    rules.push((pattern.to_string(), check_storage_class(class)?))
+                }
+                None => default = Some(check_storage_class(&value)?),
+            }
+        }
+        let default = match default {
+            Some(class) => Some(class),
+            None => Config::selected_target(matches)?
+                .and_then(|target| target.storage_class.as_deref())
+                .map(check_storage_class)
+                .transpose()?,
+        };
+        Ok(StorageClasses { rules, default })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.default.is_none()
+    }
+
+    pub fn for_path(&self, path: &Path) -> Option<String> {
+        let path = path.to_string_lossy();
+        self.rules
+            .iter()
+            .find(|(pattern, _)| path.contains(pattern.as_str()))
+            .map(|(_, class)| class.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// Chooses the codec of each planned file from `--compress`, or else from the backup set.
+pub struct Compression {
+    mode: Option<String>,
+}
+
+impl Compression {
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let mode = match matches.get_one::<String>("compress") {
+            Some(mode) => Some(mode.clone()),
+            None => Config::selected_set(matches)?.and_then(|set| set.compress.clone()),
+        };
+        match mode.as_deref() {
+            None | Some("zstd" | "brotli" | "auto" | "none") => Ok(Compression { mode }),
+            Some(mode) => Err(Error::Config(format!(
+                "unknown compression '{}'; expected zstd, brotli, auto or none",
+                mode
+            ))
+            .into()),
+        }
+    }
+
+    pub fn for_path(&self, path: &Path) -> Option<Codec> {
+        match self.mode.as_deref() {
+            Some("zstd") => Some(Codec::Zstd),
+            Some("brotli") => Some(Codec::Brotli),
+            Some("auto") => compress::auto(path),
+            _ => None,
+        }
+    }
+}
+
+/// Groups files smaller than the threshold from `--pack`, or else from the backup set, into pack
+/// objects. Files are packed in key order with others of the same storage class, and a pack is
+/// closed once it would grow beyond `PACK_SIZE`.
+pub struct Packing {
+    threshold: Option<u64>,
+}
+
+impl Packing {
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let threshold = match matches.get_one::<u64>("pack") {
+            Some(threshold) => Some(*threshold),
+            None => Config::selected_set(matches)?
+                .and_then(|set| set.pack.as_deref())
+                .map(|size| {
+                    parse_pack_threshold(size).map_err(|err| Error::Config(err.to_string()))
+                })
+                .transpose()?,
+        };
+        Ok(Packing { threshold })
+    }
+
+    /// Sets the pack of each entry which is packed, returning the number of packs. Members of a
+    /// pack are kept next to each other, so that `push` uploads each pack once.
+    pub fn assign(&self, entries: &mut [PlanEntry]) -> usize {
+        let Some(threshold) = self.threshold else {
+            return 0;
+        };
+        let mut small = entries
+            .iter_mut()
+            .filter(|entry| entry.size < threshold)
+            .collect::<Vec<_>>();
+        small.sort_by(|a, b| (&a.storage_class, &a.key).cmp(&(&b.storage_class, &b.key)));
+
+        let mut packs: Vec<Vec<&mut PlanEntry>> = Vec::new();
+        let mut size = 0;
+        for entry in small {
+            let same_class = packs
+                .last()
+                .and_then(|pack| pack.first())
+                .is_some_and(|first| first.storage_class == entry.storage_class);
+            if !same_class || size + entry.size > PACK_SIZE {
+                packs.push(Vec::new());
+                size = 0;
+            }
+            size += entry.size;
+            packs.last_mut().unwrap().push(entry);
+        }
+
+        // a pack of one file would only add a request to read it
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut num_packs = 0;
+        for pack in packs.into_iter().filter(|pack| pack.len() > 1) {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&created.as_nanos().to_le_bytes());
+            for entry in &pack {
+                hasher.update(entry.key.as_bytes());
+                hasher.update(entry.hash.as_bytes());
+            }
+            let pack_key = format!("{}{}", PACK_PREFIX, &hasher.finalize().to_string()[..32]);
+            for entry in pack {
+                entry.pack = Some(pack_key.clone());
+            }
+            num_packs += 1;
+        }
+        entries.sort_by(|a, b| (&a.pack, &a.key).cmp(&(&b.pack, &b.key)));
+        num_packs
+    }
+}
+
+/// Parses the size under which `--pack` packs files.
+pub fn parse_pack_threshold(size: &str) -> anyhow::Result<u64> {
+    match parse_size(size)? {
+        threshold if threshold > MAX_PACK_THRESHOLD => Err(anyhow!(
+            "pack threshold must be at most {}M",
+            MAX_PACK_THRESHOLD / 1024 / 1024
+        )),
+        threshold => Ok(threshold),
+    }
+}
+
+/// Reads `--prefix`, or else the prefix of the configured target, ensuring that a prefix ends
+/// with a slash.
+pub fn key_prefix(matches: &ArgMatches, named: Option<&TargetConfig>) -> String {
+    let prefix = matches
+        .get_one::<String>("prefix")
+        .or(named.and_then(|target| target.prefix.as_ref()));
+    match prefix.map(|p| p.trim_start_matches('/')) {
+        Some(p) if !p.is_empty() && !p.ends_with('/') => format!("{}/", p),
+        Some(p) => p.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Hashes a local file as it is read, keyed with `hash_key` if the target keys its hashes.
+pub fn hash_file(path: &Path, hash_key: Option<&[u8; 32]>) -> anyhow::Result<String> {
+    let mut hasher = match hash_key {
+        Some(key) => blake3::Hasher::new_keyed(key),
+        None => blake3::Hasher::new(),
+    };
+    let file =
+        std::fs::File::open(path).map_err(|err| anyhow!("could not read {:?}: {}", path, err))?;
+    hasher.update_reader(file)?;
+    Ok(hasher.finalize().to_string())
+}
+
+/// Stats and hashes a local file, keying it by `prefix` followed by its path relative to `base_path`.
+/// The hash is keyed with `hash_key` if the target keys its hashes.
+pub fn scan_file(
+    path: PathBuf,
+    base_path: &str,
+    prefix: &str,
+    hash_key: Option<&[u8; 32]>,
+) -> anyhow::Result<PlanEntry> {
+    let metadata = match std::fs::metadata(&path) {
+        Ok(m) => m,
+        Err(err) => return Err(anyhow!("could not stat {:?}: {}", &path, err)),
+    };
+    let modified = metadata.modified()?;
+    let local_modified_time = modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let dt = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(local_modified_time));
+    let dt_utc = DateTime::<Utc>::from(dt);
+    let timestamp = dt_utc.timestamp() as u64;
+
+    let hash = hash_file(&path, hash_key)?;
+
+    let key = format!(
+        "{}{}",
+        prefix,
+        path.to_str()
+            .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &path))?
+            .replace(&format!("{}/", base_path), "")
+    );
+
+    Ok(PlanEntry {
+        key,
+        path,
+        hash,
+        modified: timestamp,
+        size: metadata.len(),
+        change: Change::New,
+        storage_class: None,
+        codec: None,
+        pack: None,
+    })
+}
+
+/// Compares a local file against the remote entries. Also returns every remote entry with the
+/// same hash, including the one at the same key if it is identical.
+pub fn compare<'a>(
+    entry: &PlanEntry,
+    remote_entries: &'a [EntriesRow],
+) -> (Change, Vec<&'a EntriesRow>) {
+    let mut same_key: Option<&EntriesRow> = None;
+    let mut existing_hashes: Vec<&EntriesRow> = Vec::new();
+    for remote in remote_entries {
+        if remote.hash == entry.hash {
+            existing_hashes.push(remote);
+        }
+        if remote.key == entry.key {
+            same_key = Some(remote);
+        }
+    }
+    let change = match same_key {
+        None => Change::New,
+        Some(remote) if remote.hash == entry.hash => Change::Identical,
+        Some(remote) if remote.modified > entry.modified => Change::RemoteNewer,
+        Some(remote) if remote.modified < entry.modified => Change::Modified,
+        Some(_) => Change::Conflict,
+    };
+    (change, existing_hashes)
+}
+
+fn filter(list: &[&String], entry: &Path) -> bool {
+    list.iter()
+        .any(|l| entry.to_string_lossy().contains(l.as_str()))
 }
@@ -1,24 +1,63 @@
 use std::path::Path;
+use std::time::UNIX_EPOCH;
 
+use anyhow::anyhow;
 use clap::ArgMatches;
 use colored::Colorize;
+use rayon::prelude::*;
 
-use crate::{Plan, Sql, S3};
+use crate::commands::plan::scan_file;
+use crate::compress::Codec;
+use crate::{
+    pack, sql::EntriesRow, uploads::UploadLog, ClientConfig, Config, Error, MultipartConfig,
+    Outcome, Plan, PlanEntry, RateLimiter, RetryPolicy, Sql, S3,
+};
 
-pub async fn push(matches: &ArgMatches) -> anyhow::Result<()> {
-    let endpoint = matches.get_one::<String>("endpoint");
-    let plan = Plan::read();
-    let num_entries = plan.entries.len();
-    let bucket_name = plan.bucket_name;
+pub async fn push(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let force = matches.get_flag("force");
+    let plan_file = Path::new(matches.get_one::<String>("plan").unwrap());
+    let mut plan = Plan::read(plan_file)?;
+    // connect with the options of the configured target the plan was made against, if any
+    let named = match &plan.target_name {
+        Some(name) => Some(Config::get()?.target(name)?),
+        None => None,
+    };
+    let mut client = ClientConfig::for_target(matches, named);
+    // default to the endpoint the plan was made against
+    client.endpoint = client.endpoint.or(plan.header.endpoint.clone());
+    let target = plan.target.clone();
 
-    println!(
-        "Pushing {} objects to bucket {}...",
-        num_entries, &bucket_name
-    );
+    if plan.entries.is_empty() {
+        println!("{}", "Plan is empty; nothing to push.".white());
+        std::fs::remove_file(plan_file)?;
+        return Ok(Outcome::NothingToDo);
+    }
 
     // TODO check for lock
     //      lock should be its own operation, i.e. s3b lock & s3b lock --release
-    let s3 = S3::new(&bucket_name, endpoint.map(|s| s.as_str())).await?;
+    let s3 = S3::new(&target, &client, RetryPolicy::from_matches(matches))
+        .await?
+        .with_rate_limit(RateLimiter::from_matches(matches, named)?)
+        .with_multipart(MultipartConfig::from_matches(matches))
+        .with_upload_log(UploadLog::read(plan_file)?);
+    // files are re-hashed as the target hashes them, so they are checked once connected
+    check_files(&mut plan, force, s3.hash_key())?;
+    let num_entries = plan.entries.len();
+    println!("Pushing {} objects to {}...", num_entries, &target);
+    let db_etag = s3.e_tag("_s3b_db/entries.jsonl").await?;
+    if db_etag != plan.db_etag {
+        if !force {
+            return Err(Error::Conflict(format!(
+                "the database in {} changed since the plan was made; run `s3b plan` again, or pass --force to push anyway",
+                &target
+            ))
+            .into());
+        }
+        println!(
+            "{}",
+            "The remote database changed since the plan was made; pushing anyway.".yellow()
+        );
+    }
     let exists = s3.key_exists("_s3b_db/entries.sql").await?;
     if exists {
         s3.get("_s3b_db/").await?;
@@ -27,23 +66,231 @@ pub async fn push(matches: &ArgMatches) -> anyhow::Result<()> {
     let mut sql = Sql::new().await?;
     let remote_entries = sql.get_entries().await?;
     let pb = indicatif::ProgressBar::new(num_entries as u64);
-    for entry in &plan.entries {
-        s3.put(Path::new(&entry.key)).await?;
-        match remote_entries.iter().find(|&e| e.key == entry.key) {
-            Some(_) => sql.update_entry(entry).await?,
-            None => sql.put_entry(entry).await?,
+    let mut num_uploaded = 0;
+    let mut failure: Option<anyhow::Error> = None;
+    // entries whose content changed after they were checked, e.g. while the push was running
+    let mut changed: Vec<String> = Vec::new();
+    // packs which held the previous content of updated entries, deleted once nothing refers to them
+    let mut replaced_packs: Vec<String> = Vec::new();
+    // the members of a pack are next to each other in the plan, and are uploaded together
+    'groups: for group in plan
+        .entries
+        .chunk_by(|a, b| a.pack.is_some() && a.pack == b.pack)
+    {
+        let result = match &group[0].pack {
+            Some(pack_key) => {
+                let files = group
+                    .iter()
+                    .map(|entry| -> anyhow::Result<_> {
+                        let codec = entry.codec.as_deref().map(Codec::parse).transpose()?;
+                        Ok((entry.path.as_path(), entry.key.as_str(), codec))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>();
+                match files {
+                    Ok(files) => s3
+                        .put_pack(pack_key, &files, group[0].storage_class.as_deref())
+                        .await
+                        .map_err(|err| err.context(format!("failed to upload pack {}", pack_key))),
+                    Err(err) => Err(err),
+                }
+            }
+            None => {
+                let entry = &group[0];
+                match entry.codec.as_deref().map(Codec::parse).transpose() {
+                    Ok(codec) => s3
+                        .put_one(
+                            &entry.path,
+                            &entry.key,
+                            entry.storage_class.as_deref(),
+                            codec,
+                        )
+                        .await
+                        .map(|uploaded| vec![uploaded])
+                        .map_err(|err| err.context(format!("failed to upload {}", &entry.key))),
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        let uploaded = match result {
+            Ok(uploaded) => uploaded,
+            Err(err) => {
+                failure = Some(err);
+                break;
+            }
         };
-        pb.inc(1);
+        for (entry, uploaded) in group.iter().zip(uploaded) {
+            if uploaded.hash != entry.hash {
+                changed.push(entry.key.clone());
+            }
+            let pack = uploaded.pack;
+            let row = match EntriesRow::new(entry) {
+                Ok(row) => row,
+                Err(err) => {
+                    failure = Some(err);
+                    break 'groups;
+                }
+            };
+            let row = EntriesRow {
+                hash: uploaded.hash,
+                etag: uploaded.e_tag,
+                checksum: uploaded.checksum,
+                size: Some(uploaded.size),
+                source: plan.source.clone(),
+                codec: uploaded.codec.map(|codec| codec.name().to_string()),
+                pack_key: pack.as_ref().map(|pack| pack.pack_key.clone()),
+                pack_offset: pack.as_ref().map(|pack| pack.offset),
+                pack_length: pack.as_ref().map(|pack| pack.length),
+                ..row
+            };
+            // a failure to record an entry stops the push like a failed upload, so that the
+            // entries recorded before it are still pushed with the database
+            let recorded = match remote_entries.iter().find(|&e| e.key == entry.key) {
+                Some(previous) => {
+                    let replaced = match &previous.pack_key {
+                        Some(pack_key) => {
+                            replaced_packs.push(pack_key.clone());
+                            Ok(())
+                        }
+                        // the object which held the previous content is no longer needed
+                        None if row.pack_key.is_some() => s3.delete(&entry.key).await.map(|_| ()),
+                        None => Ok(()),
+                    };
+                    match replaced {
+                        Ok(()) => sql.update_entry(&row).await,
+                        Err(err) => Err(err),
+                    }
+                }
+                None => sql.put_entry(&row).await,
+            };
+            if let Err(err) = recorded {
+                failure = Some(err);
+                break 'groups;
+            }
+            num_uploaded += 1;
+            pb.inc(1);
+        }
     }
     pb.finish();
+    match pack::delete_unreferenced(&s3, &mut sql, replaced_packs.iter().map(String::as_str)).await
+    {
+        Ok(0) => {}
+        Ok(num_packs_deleted) => println!(
+            "Deleted {} pack objects which no longer hold any current content.",
+            num_packs_deleted
+        ),
+        Err(err) => failure = failure.or(Some(err)),
+    }
+    if !changed.is_empty() {
+        println!(
+            "{}",
+            "Content changed since the plan was made; the uploaded content was recorded for:"
+                .yellow()
+        );
+        for key in &changed {
+            println!("    {}", key);
+        }
+    }
+
+    // the database is pushed even after a failure, so that objects which were uploaded are tracked;
+    // it is left alone if nothing changed, since rewriting it changes its ETag
+    if num_uploaded > 0 {
+        s3.put(Path::new("_s3b_db/")).await?;
+    }
+    std::fs::remove_dir_all("_s3b_db")?;
+
+    if let Some(err) = failure {
+        // keep only what is left to upload, so that pushing again resumes where this push stopped
+        plan.entries.drain(..num_uploaded);
+        plan.db_etag = s3.e_tag("_s3b_db/entries.jsonl").await?;
+        plan.write(plan_file)?;
+        if num_uploaded == 0 {
+            return Err(err);
+        }
+        let partial = Error::Partial(format!(
+            "uploaded {} of {} objects ({} retries); run `s3b push` again to resume",
+            num_uploaded,
+            num_entries,
+            s3.retry_count()
+        ));
+        return Err(err.context(partial));
+    }
+
     println!(
         "{}",
-        format!("Done! Uploaded {} objects.", num_entries).green()
+        format!(
+            "Done! Uploaded {} objects ({} retries).",
+            num_entries,
+            s3.retry_count()
+        )
+        .green()
     );
+    std::fs::remove_file(plan_file)?;
 
-    s3.put(Path::new("_s3b_db/")).await?;
-    std::fs::remove_dir_all("_s3b_db").unwrap();
-    std::fs::remove_file("s3b_plan.bin").unwrap();
+    Ok(Outcome::Done)
+}
+
+/// Checks each file in the plan against the size and modified time recorded when it was planned,
+/// re-hashing those which differ. Files whose content changed are refused unless `force` is set,
+/// in which case the plan is updated to push their current content and deleted files are dropped.
+fn check_files(plan: &mut Plan, force: bool, hash_key: Option<&[u8; 32]>) -> anyhow::Result<()> {
+    let base_path = plan.base_path.clone();
+    let base_path_str = base_path
+        .to_str()
+        .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &base_path))?;
+    let checked = plan
+        .entries
+        .par_iter()
+        .map(|entry| -> anyhow::Result<Option<PlanEntry>> {
+            let metadata = match std::fs::metadata(&entry.path) {
+                Ok(m) => m,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(anyhow!("could not stat {:?}: {}", &entry.path, err)),
+            };
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+            if metadata.len() == entry.size && modified == entry.modified {
+                return Ok(Some(entry.clone()));
+            }
+            let scanned = scan_file(entry.path.clone(), base_path_str, &plan.prefix, hash_key)?;
+            Ok(Some(PlanEntry {
+                key: entry.key.clone(),
+                change: entry.change,
+                storage_class: entry.storage_class.clone(),
+                codec: entry.codec.clone(),
+                pack: entry.pack.clone(),
+                ..scanned
+            }))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
+    let mut stale: Vec<(&str, &str)> = Vec::new();
+    for (entry, current) in plan.entries.iter().zip(&checked) {
+        match current {
+            None => stale.push((&entry.key, "deleted")),
+            Some(current) if current.hash != entry.hash => stale.push((&entry.key, "modified")),
+            _ => {}
+        }
+    }
+    if !stale.is_empty() {
+        for (key, reason) in &stale {
+            println!("    {:<10}{}", format!("{}:", reason).yellow(), key);
+        }
+        if !force {
+            return Err(Error::Conflict(format!(
+                "{} files changed since the plan was made; run `s3b plan` again, or pass --force to push their current content",
+                stale.len()
+            ))
+            .into());
+        }
+        println!(
+            "{}",
+            format!(
+                "{} files changed since the plan was made; pushing their current content.",
+                stale.len()
+            )
+            .yellow()
+        );
+    }
+    // files which were only touched keep their place in the plan, with their new modified time
+    plan.entries = checked.into_iter().flatten().collect();
     Ok(())
 }
@@ -0,0 +1,408 @@
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use aws_sdk_s3::types::Tier;
+use clap::ArgMatches;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::commands::plan::hash_file;
+use crate::{
+    compress::Codec, pack::PackRange, s3::ArchiveState, sql::quote, ClientConfig, Config, Error,
+    Outcome, RateLimiter, RetryPolicy, Sql, Target, S3,
+};
+
+const RESTORE_FILE: &str = "./s3b_restore.bin";
+
+/// Restores which have been requested but not yet downloaded, so that `restore --continue` can
+/// pick them up later. Written to the current directory while restores are pending.
+#[derive(Debug, Serialize, Deserialize)]
+struct RestoreState {
+    target: Target,
+    /// The name of the target in `s3b.toml`, if it was given by name
+    target_name: Option<String>,
+    endpoint: Option<String>,
+    /// The canonical path of the directory objects are restored into
+    dir: PathBuf,
+    tier: String,
+    days: i32,
+    pending: Vec<PendingRestore>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingRestore {
+    key: String,
+    hash: String,
+    modified: u64,
+    /// How the object's content was compressed, as recorded in the database
+    codec: Option<String>,
+    /// Where the content is stored, if the object was packed
+    pack: Option<PackRange>,
+}
+
+impl PendingRestore {
+    /// The object which is restored and downloaded from: the entry's pack, if it is packed.
+    fn object_key(&self) -> &str {
+        self.pack.as_ref().map_or(&self.key, |pack| &pack.pack_key)
+    }
+}
+
+impl RestoreState {
+    fn read() -> anyhow::Result<Option<Self>> {
+        match std::fs::read(RESTORE_FILE) {
+            Ok(bytes) => bincode::deserialize(&bytes).map(Some).map_err(|err| {
+                Error::Integrity(format!("could not decode {}: {}", RESTORE_FILE, err)).into()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the state, removing the file once no restores are pending.
+    fn write(&self) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            match std::fs::remove_file(RESTORE_FILE) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        } else {
+            std::fs::write(RESTORE_FILE, bincode::serialize(&self)?).map_err(|err| err.into())
+        }
+    }
+}
+
+pub async fn restore(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let wait = matches.get_flag("wait");
+    let poll = Duration::from_secs(60 * matches.get_one::<u64>("poll").unwrap());
+
+    let (mut state, s3) = if matches.get_flag("continue") {
+        let state = RestoreState::read()?
+            .ok_or_else(|| Error::NotFound(format!("no pending restores in {}", RESTORE_FILE)))?;
+        let named = match &state.target_name {
+            Some(name) => Some(Config::get()?.target(name)?),
+            None => None,
+        };
+        let mut client = ClientConfig::for_target(matches, named);
+        client.endpoint = client.endpoint.or(state.endpoint.clone());
+        let s3 = S3::new(&state.target, &client, RetryPolicy::from_matches(matches)).await?;
+        (state, s3)
+    } else {
+        if RestoreState::read()?.is_some() {
+            return Err(Error::Conflict(format!(
+                "restores are already pending in {}; run `s3b restore --continue`, or remove the file to start over",
+                RESTORE_FILE
+            ))
+            .into());
+        }
+        start(matches).await?
+    };
+    // a continued restore is limited like the one which started it
+    let named = match &state.target_name {
+        Some(name) => Some(Config::get()?.target(name)?),
+        None => None,
+    };
+    let s3 = s3.with_rate_limit(RateLimiter::from_matches(matches, named)?);
+    let tier = Tier::from(parse_tier(&state.tier)?.as_str());
+
+    let total = state.pending.len();
+    if total == 0 {
+        println!("{}", "Every object is already restored.".white());
+        return Ok(Outcome::NothingToDo);
+    }
+    println!(
+        "Restoring {} objects from {} to {}...",
+        total,
+        &state.target,
+        state.dir.display()
+    );
+    loop {
+        let mut states = Vec::with_capacity(state.pending.len());
+        for entry in &state.pending {
+            match s3.archive_state(entry.object_key()).await {
+                Ok(archive_state) => states.push(archive_state),
+                Err(err) => {
+                    let key = entry.key.clone();
+                    state.write()?;
+                    return Err(err.context(format!("failed to restore {}", key)));
+                }
+            }
+        }
+        let Sorted {
+            ready,
+            archived,
+            restoring,
+        } = sort(std::mem::take(&mut state.pending), states);
+
+        // until they are downloaded, every entry stays pending so that `--continue` picks up
+        // from here
+        let num_requested = archived.len();
+        state.pending = restoring;
+        for (entry, class) in archived {
+            let result = s3
+                .request_restore(entry.object_key(), &class, tier.clone(), state.days)
+                .await;
+            state.pending.push(entry);
+            if let Err(err) = result {
+                let key = state.pending.last().unwrap().key.clone();
+                state.pending.extend(ready);
+                state.write()?;
+                return Err(err.context(format!("failed to restore {}", key)));
+            }
+        }
+        let num_downloaded = ready.len();
+        let mut ready = ready.into_iter();
+        while let Some(entry) = ready.next() {
+            if let Err(err) = download(&s3, &state.dir, &entry).await {
+                let key = entry.key.clone();
+                state.pending.push(entry);
+                state.pending.extend(ready);
+                state.write()?;
+                return Err(err.context(format!("failed to restore {}", key)));
+            }
+        }
+        state.write()?;
+
+        println!(
+            "Downloaded {} objects; requested {} restores, {} pending.",
+            num_downloaded,
+            num_requested,
+            state.pending.len()
+        );
+        if state.pending.is_empty() {
+            println!("{}", format!("Done! Restored {} objects.", total).green());
+            return Ok(Outcome::Done);
+        }
+        if !wait {
+            println!(
+                "{}",
+                format!(
+                    "{} objects are being restored, which can take hours; run `s3b restore --continue` to download them once they are available.",
+                    state.pending.len()
+                )
+                .yellow()
+            );
+            return Ok(Outcome::Pending);
+        }
+        tokio::time::sleep(poll).await;
+    }
+}
+
+/// Pending entries sorted by the state of their objects.
+#[derive(Debug, Default)]
+struct Sorted {
+    /// Entries whose objects can be downloaded now
+    ready: Vec<PendingRestore>,
+    /// Entries whose objects must be restored first, with their storage class. This includes
+    /// entries whose restored copy expired before it was downloaded.
+    archived: Vec<(PendingRestore, String)>,
+    /// Entries whose objects are still being restored
+    restoring: Vec<PendingRestore>,
+}
+
+/// Sorts pending entries by the archive states of their objects, given in the same order.
+fn sort(pending: Vec<PendingRestore>, states: Vec<ArchiveState>) -> Sorted {
+    let mut sorted = Sorted::default();
+    for (entry, archive_state) in pending.into_iter().zip(states) {
+        match archive_state {
+            ArchiveState::Available => sorted.ready.push(entry),
+            ArchiveState::Archived(class) => sorted.archived.push((entry, class)),
+            ArchiveState::Restoring => sorted.restoring.push(entry),
+        }
+    }
+    sorted
+}
+
+/// Selects the entries to restore and checks the destination, returning the new restore state.
+async fn start(matches: &ArgMatches) -> anyhow::Result<(RestoreState, S3)> {
+    let target = Target::from_matches(matches)?;
+    let client = ClientConfig::from_matches(matches)?;
+    let dir = PathBuf::from(matches.get_one::<String>("to").unwrap());
+    let dir = dir
+        .canonicalize()
+        .map_err(|err| Error::NotFound(format!("could not resolve {}: {}", dir.display(), err)))?;
+    let force = matches.get_flag("force");
+
+    let mut conditions: Vec<String> = Vec::new();
+    if let Some(w) = matches.get_one::<String>("where") {
+        conditions.push(format!("({})", w));
+    }
+    if let Some(path) = matches.get_one::<String>("path") {
+        // a trailing slash selects every object under the path, as for `drop`
+        conditions.push(match path.ends_with('/') {
+            true => format!("key LIKE {}", quote(&format!("{}%", path))),
+            false => format!("key={}", quote(path)),
+        });
+    }
+
+    let s3 = S3::new(&target, &client, RetryPolicy::from_matches(matches)).await?;
+    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
+    if exists {
+        s3.get("_s3b_db/").await?;
+    }
+    let mut sql = Sql::new().await?;
+    let entries = match conditions.is_empty() {
+        true => sql.get_entries().await?,
+        false => sql.get_entries_where(&conditions.join(" AND ")).await?,
+    };
+    std::fs::remove_dir_all("_s3b_db")?;
+    if entries.is_empty() {
+        return Err(Error::NotFound(format!("no objects in {} match", target)).into());
+    }
+
+    let mut pending = Vec::with_capacity(entries.len());
+    let mut existing: Vec<&str> = Vec::new();
+    for entry in &entries {
+        let dest = destination(&dir, &entry.key)?;
+        if dest.exists() && !force {
+            if hash_file(&dest, s3.hash_key())? != entry.hash {
+                existing.push(&entry.key);
+            }
+            // files which are already restored are left alone
+            continue;
+        }
+        pending.push(PendingRestore {
+            key: entry.key.clone(),
+            hash: entry.hash.clone(),
+            modified: entry.modified,
+            codec: entry.codec.clone(),
+            pack: entry.pack(),
+        });
+    }
+    if !existing.is_empty() {
+        for key in &existing {
+            println!("    {:<10}{}", "exists:".yellow(), key);
+        }
+        return Err(Error::Conflict(format!(
+            "{} files already exist in {} with different content; pass --force to overwrite them",
+            existing.len(),
+            dir.display()
+        ))
+        .into());
+    }
+
+    let state = RestoreState {
+        target,
+        target_name: Config::target_name(matches)?,
+        endpoint: client.endpoint.clone(),
+        dir,
+        tier: matches.get_one::<String>("tier").unwrap().clone(),
+        days: *matches.get_one::<i32>("days").unwrap(),
+        pending,
+    };
+    Ok((state, s3))
+}
+
+/// Downloads an entry into the directory, checking its content against the database and
+/// restoring its modified time.
+async fn download(s3: &S3, dir: &Path, entry: &PendingRestore) -> anyhow::Result<()> {
+    let dest = destination(dir, &entry.key)?;
+    let codec = entry.codec.as_deref().map(Codec::parse).transpose()?;
+    s3.download(&entry.key, &dest, &entry.hash, codec, entry.pack.as_ref())
+        .await?;
+    File::options()
+        .write(true)
+        .open(&dest)?
+        .set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))?;
+    Ok(())
+}
+
+/// Parses a restore tier, as S3 spells it.
+pub fn parse_tier(value: &str) -> anyhow::Result<String> {
+    match value.to_ascii_lowercase().as_str() {
+        "standard" => Ok("Standard".into()),
+        "bulk" => Ok("Bulk".into()),
+        "expedited" => Ok("Expedited".into()),
+        _ => Err(Error::Config(format!(
+            "unknown restore tier '{}'; expected standard, bulk or expedited",
+            value
+        ))
+        .into()),
+    }
+}
+
+/// The path a key is restored to, refusing keys which would escape the directory.
+fn destination(dir: &Path, key: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(key);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(Error::Config(format!(
+            "refusing to restore key '{}' outside of {}",
+            key,
+            dir.display()
+        ))
+        .into());
+    }
+    Ok(dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(key: &str) -> PendingRestore {
+        PendingRestore {
+            key: key.into(),
+            hash: String::new(),
+            modified: 0,
+            codec: None,
+            pack: None,
+        }
+    }
+
+    fn keys(entries: &[PendingRestore]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.key.as_str()).collect()
+    }
+
+    #[test]
+    fn entries_are_sorted_by_archive_state() {
+        let sorted = sort(
+            vec![
+                pending("a.txt"),
+                pending("b.txt"),
+                pending("c.txt"),
+                pending("d.txt"),
+            ],
+            vec![
+                ArchiveState::Restoring,
+                ArchiveState::Available,
+                ArchiveState::Archived("DEEP_ARCHIVE".into()),
+                ArchiveState::Available,
+            ],
+        );
+        assert_eq!(keys(&sorted.ready), ["b.txt", "d.txt"]);
+        assert_eq!(keys(&sorted.restoring), ["a.txt"]);
+        assert_eq!(sorted.archived.len(), 1);
+        assert_eq!(sorted.archived[0].0.key, "c.txt");
+        assert_eq!(sorted.archived[0].1, "DEEP_ARCHIVE");
+    }
+
+    #[test]
+    fn later_passes_download_finished_restores() {
+        // the first pass requests restores; a later one, from `--wait` or `--continue`, finds
+        // some finished and one whose restored copy expired before it was downloaded
+        let first = sort(
+            vec![pending("a.txt"), pending("b.txt"), pending("c.txt")],
+            vec![
+                ArchiveState::Archived("GLACIER".into()),
+                ArchiveState::Archived("GLACIER".into()),
+                ArchiveState::Archived("GLACIER".into()),
+            ],
+        );
+        assert!(first.ready.is_empty());
+        let pending = first.archived.into_iter().map(|(entry, _)| entry).collect();
+        let second = sort(
+            pending,
+            vec![
+                ArchiveState::Available,
+                ArchiveState::Restoring,
+                ArchiveState::Archived("GLACIER".into()),
+            ],
+        );
+        assert_eq!(keys(&second.ready), ["a.txt"]);
+        assert_eq!(keys(&second.restoring), ["b.txt"]);
+        assert_eq!(second.archived[0].0.key, "c.txt");
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use cli_table::{Cell, Style, Table};
+use colored::Colorize;
+use serde_json::json;
+
+use crate::{Outcome, Plan, PlanEntry, PLAN_FILE};
+
+pub async fn show_plan(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let file = matches
+        .get_one::<String>("file")
+        .map(|s| s.as_str())
+        .unwrap_or(PLAN_FILE);
+    let action = matches.get_one::<String>("action");
+    let filters: Vec<&String> = match matches.get_many("filter") {
+        Some(m) => m.collect(),
+        None => Vec::new(),
+    };
+    let as_json = matches.get_flag("json");
+
+    let plan = Plan::read(Path::new(file))?;
+    let entries = plan
+        .entries
+        .iter()
+        .filter(|entry| action.is_none_or(|a| entry.change.action() == a))
+        .filter(|entry| {
+            filters.is_empty() || filters.iter().any(|f| entry.key.contains(f.as_str()))
+        })
+        .collect::<Vec<&PlanEntry>>();
+    let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+
+    if as_json {
+        let output = json!({
+            "header": plan.header,
+            "target": plan.target,
+            "base_path": plan.base_path,
+            "prefix": plan.prefix,
+            "source": plan.source,
+            "entries": entries
+                .iter()
+                .map(|entry| json!({
+                    "key": entry.key,
+                    "path": entry.path,
+                    "action": entry.change.action(),
+                    "size": entry.size,
+                    "hash": entry.hash,
+                    "modified": entry.modified,
+                    "conflict": entry.change.conflict_reason(),
+                    "storage_class": entry.storage_class,
+                    "codec": entry.codec,
+                    "pack": entry.pack,
+                }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(Outcome::Done);
+    }
+
+    let created = DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(plan.header.created));
+    println!(
+        "Plan for {} from {}, created {} by s3b {}",
+        plan.target.to_string().bold(),
+        plan.base_path.display().to_string().bold(),
+        created.format("%Y-%m-%d %H:%M:%S UTC"),
+        plan.header.s3b_version
+    );
+    if !plan.prefix.is_empty() || plan.source.is_some() {
+        println!(
+            "Keys prefixed with '{}', source {}",
+            plan.prefix,
+            plan.source.as_deref().unwrap_or("not set")
+        );
+    }
+    if entries.is_empty() {
+        println!("{}", "No entries in the plan match.".white());
+        return Ok(Outcome::NothingToDo);
+    }
+
+    let table = entries
+        .iter()
+        .map(|entry| {
+            vec![
+                entry.change.action().cell(),
+                entry.key.clone().cell(),
+                entry.size.cell(),
+                entry.hash.clone().cell(),
+                entry.change.conflict_reason().unwrap_or_default().cell(),
+                entry.storage_class.clone().unwrap_or_default().cell(),
+                entry.codec.clone().unwrap_or_default().cell(),
+                entry.pack.clone().unwrap_or_default().cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "Action".cell().bold(true),
+            "Key".cell().bold(true),
+            "Size".cell().bold(true),
+            "Hash".cell().bold(true),
+            "Conflict".cell().bold(true),
+            "Storage Class".cell().bold(true),
+            "Codec".cell().bold(true),
+            "Pack".cell().bold(true),
+        ]);
+    println!("{}", table.display()?);
+    println!(
+        "{}",
+        format!(
+            "{} of {} entries, {} bytes in total.",
+            entries.len(),
+            plan.entries.len(),
+            total_size
+        )
+        .white()
+    );
+
+    Ok(Outcome::Done)
+}
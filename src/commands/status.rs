@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use clap::ArgMatches;
+use colored::{ColoredString, Colorize};
+use rayon::prelude::*;
+
+use crate::commands::plan::{
+    compare, find_files, is_selected, key_prefix, scan_file, Change, Selection,
+};
+use crate::{ClientConfig, Config, Outcome, RetryPolicy, Sql, Target, S3};
+
+pub async fn status(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
+
+    let prefix = key_prefix(matches, Config::selected_target(matches)?);
+
+    let selection = Selection::from_matches(matches)?;
+    let include = selection.include();
+    let exclude = selection.exclude();
+    let filtered_entries = find_files(&selection.root, &include, &exclude)?;
+
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
+    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
+    if exists {
+        s3.get("_s3b_db/").await?;
+    }
+
+    let mut sql = Sql::new().await?;
+    let remote_entries = sql.get_entries().await?;
+    std::fs::remove_dir_all("_s3b_db")?;
+
+    let base_path = selection.root.clone();
+    let base_path_str = base_path
+        .to_str()
+        .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &base_path))?;
+    let changes: Mutex<Vec<(String, Change)>> =
+        Mutex::new(Vec::with_capacity(filtered_entries.len()));
+    let pb = indicatif::ProgressBar::new(filtered_entries.len() as u64);
+    filtered_entries
+        .into_par_iter()
+        .try_for_each(|path| -> anyhow::Result<()> {
+            let entry = scan_file(path, base_path_str, &prefix, s3.hash_key())?;
+            let (change, _) = compare(&entry, &remote_entries);
+            changes.lock().unwrap().push((entry.key, change));
+            pb.inc(1);
+            Ok(())
+        })?;
+    pb.finish_and_clear();
+    let mut changes = changes.into_inner().unwrap();
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // entries which were pushed from this directory, would still be selected, but are gone
+    let local_keys = changes
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .collect::<HashSet<_>>();
+    let mut deleted = remote_entries
+        .iter()
+        .filter(|remote| {
+            let path = Path::new(&remote.path);
+            path.starts_with(&base_path)
+                && remote.key.starts_with(&prefix)
+                && is_selected(&include, &exclude, path)
+                && !local_keys.contains(remote.key.as_str())
+        })
+        .map(|remote| remote.key.as_str())
+        .collect::<Vec<_>>();
+    deleted.sort();
+
+    let count = |wanted: Change| changes.iter().filter(|(_, c)| *c == wanted).count();
+    let num_identical = count(Change::Identical);
+    let num_new = count(Change::New);
+    let num_modified = count(Change::Modified) + count(Change::Conflict);
+    let num_remote_newer = count(Change::RemoteNewer);
+
+    println!(
+        "On {}, comparing {}",
+        target.to_string().bold(),
+        base_path_str.bold()
+    );
+
+    let local_changes = changes
+        .iter()
+        .filter_map(|(key, change)| match change {
+            Change::New => Some(("new:".green(), key.as_str(), "")),
+            Change::Modified => Some(("modified:".yellow(), key.as_str(), "")),
+            Change::Conflict => Some(("modified:".yellow(), key.as_str(), " (same modified time)")),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    print_section("Changes not backed up:", &local_changes);
+
+    let remote_newer = changes
+        .iter()
+        .filter(|(_, change)| *change == Change::RemoteNewer)
+        .map(|(key, _)| ("remote newer:".cyan(), key.as_str(), ""))
+        .collect::<Vec<_>>();
+    print_section("Remote is newer than local:", &remote_newer);
+
+    let deleted_lines = deleted
+        .iter()
+        .map(|&key| ("deleted:".red(), key, ""))
+        .collect::<Vec<_>>();
+    print_section("Deleted locally:", &deleted_lines);
+
+    println!(
+        "\n{} new, {} modified, {} remote newer, {} deleted locally, {} identical",
+        num_new,
+        num_modified,
+        num_remote_newer,
+        deleted.len(),
+        num_identical
+    );
+
+    if num_new + num_modified + num_remote_newer + deleted.len() == 0 {
+        println!(
+            "{}",
+            "Nothing to back up; local tree matches the bucket.".green()
+        );
+        Ok(Outcome::Done)
+    } else {
+        Ok(Outcome::Differences)
+    }
+}
+
+fn print_section(title: &str, lines: &[(ColoredString, &str, &str)]) {
+    if lines.is_empty() {
+        return;
+    }
+    println!("\n{}", title.bold());
+    for (label, key, note) in lines {
+        println!("        {:<14}{}{}", label, key, note);
+    }
+}
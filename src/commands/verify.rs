@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::ArgMatches;
+use cli_table::{Cell, Style, Table};
+use colored::Colorize;
+use rand::seq::SliceRandom;
+
+use crate::{
+    compress::Codec, crypto::KEY_OBJECT, sql::EntriesRow, ClientConfig, Error, Outcome,
+    RetryPolicy, Sql, Target, S3,
+};
+
+pub async fn verify(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    let target = Target::from_matches(matches)?;
+    let deep = matches.get_flag("deep");
+    let sample = matches.get_one::<f64>("sample");
+
+    // TODO check for lock
+    //      lock should be its own operation, i.e. s3b lock & s3b lock --release
+    let s3 = S3::new(
+        &target,
+        &ClientConfig::from_matches(matches)?,
+        RetryPolicy::from_matches(matches),
+    )
+    .await?;
+    let exists = s3.key_exists("_s3b_db/entries.sql").await?;
+    if exists {
+        s3.get("_s3b_db/").await?;
+    }
+
+    let mut sql = Sql::new().await?;
+    let remote_entries = sql.get_entries().await?;
+    std::fs::remove_dir_all("_s3b_db")?;
+
+    let spinner = indicatif::ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(120));
+    spinner.set_message("Listing objects...");
+    let objects = s3
+        .list_objects(None)
+        .await?
+        .into_iter()
+        .map(|meta| (meta.location.to_string(), meta.size as u64))
+        .filter(|(key, _)| !key.starts_with("_s3b_db/") && key != KEY_OBJECT)
+        .collect::<HashMap<_, _>>();
+    spinner.finish_with_message(format!("Found {} objects", objects.len()));
+
+    let mut problems: Vec<(String, &str, String)> = Vec::new();
+    let mut present: Vec<&EntriesRow> = Vec::new();
+    for entry in &remote_entries {
+        if let Some(pack) = entry.pack() {
+            match objects.get(&pack.pack_key) {
+                None => problems.push((
+                    entry.key.clone(),
+                    "missing from bucket",
+                    format!("pack {} does not exist", pack.pack_key),
+                )),
+                Some(&size) if pack.offset + pack.length > size => problems.push((
+                    entry.key.clone(),
+                    "size mismatch",
+                    format!(
+                        "pack {} has {} bytes, but the object ends at byte {}",
+                        pack.pack_key,
+                        size,
+                        pack.offset + pack.length
+                    ),
+                )),
+                _ => present.push(entry),
+            }
+            continue;
+        }
+        match (objects.get(&s3.object_key(&entry.key)), entry.size) {
+            (None, _) => problems.push((entry.key.clone(), "missing from bucket", String::new())),
+            (Some(&size), Some(expected)) if size != expected => problems.push((
+                entry.key.clone(),
+                "size mismatch",
+                format!("expected {} bytes, found {}", expected, size),
+            )),
+            _ => present.push(entry),
+        }
+    }
+    let keys = remote_entries
+        .iter()
+        .map(|e| e.pack_key.clone().unwrap_or_else(|| s3.object_key(&e.key)))
+        .collect::<HashSet<_>>();
+    for (key, size) in &objects {
+        if !keys.contains(key) {
+            problems.push((key.clone(), "not in database", format!("{} bytes", size)));
+        }
+    }
+
+    let to_hash = if deep {
+        present
+    } else if let Some(percent) = sample {
+        let amount = (present.len() as f64 * percent / 100.0).ceil() as usize;
+        present
+            .choose_multiple(&mut rand::thread_rng(), amount)
+            .copied()
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if !to_hash.is_empty() {
+        println!("Re-hashing {} objects...", to_hash.len());
+        let pb = indicatif::ProgressBar::new(to_hash.len() as u64);
+        for entry in &to_hash {
+            let codec = entry.codec.as_deref().map(Codec::parse).transpose()?;
+            match s3
+                .hash_object(&entry.key, codec, entry.pack().as_ref())
+                .await
+            {
+                Ok(hash) if hash != entry.hash => problems.push((
+                    entry.key.clone(),
+                    "hash mismatch",
+                    format!("expected {}, found {}", entry.hash, hash),
+                )),
+                Ok(_) => {}
+                // an encrypted object which fails to decrypt has been tampered with or corrupted
+                Err(err) => match err.downcast_ref::<Error>() {
+                    Some(Error::Integrity(details)) => {
+                        problems.push((entry.key.clone(), "corrupt", details.clone()))
+                    }
+                    _ => return Err(err),
+                },
+            }
+            pb.inc(1);
+        }
+        pb.finish();
+    }
+
+    if problems.is_empty() {
+        println!(
+            "\n{}",
+            format!(
+                "Verified {} objects against the database; {} re-hashed. No problems found.",
+                remote_entries.len(),
+                to_hash.len()
+            )
+            .green()
+        );
+        return Ok(Outcome::Done);
+    }
+
+    problems.sort();
+    let table = problems
+        .iter()
+        .map(|(key, problem, details)| {
+            vec![key.clone().cell(), problem.cell(), details.clone().cell()]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "Key".cell().bold(true),
+            "Problem".cell().bold(true),
+            "Details".cell().bold(true),
+        ]);
+    println!("{}", table.display()?);
+
+    Err(Error::Integrity(format!("found {} problems in {}", problems.len(), target)).into())
+}
+
+/// Parses a sample size such as `5%` or `5` into a percentage.
+pub fn parse_percent(percent: &str) -> anyhow::Result<f64> {
+    match percent.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(p),
+        _ => Err(anyhow!(
+            "invalid percentage '{}'; expected e.g. 5%",
+            percent
+        )),
+    }
+}
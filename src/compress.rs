@@ -0,0 +1,259 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::Error;
+
+/// Extensions of formats which are already compressed, which `--compress auto` leaves alone.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "cab", "deb", "docx", "epub", "flac", "gif",
+    "gz", "heic", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3",
+    "mp4", "odt", "ogg", "opus", "pdf", "png", "pptx", "rar", "rpm", "tgz", "txz", "webm", "webp",
+    "whl", "xlsx", "xz", "zip", "zst",
+];
+const ZSTD_LEVEL: i32 = 9;
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+
+/// How an object's content is compressed before it is uploaded, recorded in the `codec` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Brotli,
+}
+
+impl Codec {
+    /// Parses a codec as recorded in the database or the plan.
+    pub fn parse(name: &str) -> anyhow::Result<Codec> {
+        match name {
+            "zstd" => Ok(Codec::Zstd),
+            "brotli" => Ok(Codec::Brotli),
+            _ => Err(Error::Integrity(format!("unknown codec '{}'", name)).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Brotli => "brotli",
+        }
+    }
+
+    /// Compresses the file at `src` into `dest`, returning the hash of the content which was
+    /// read, as `hasher` hashes it, and the compressed size.
+    pub fn compress(
+        &self,
+        src: &Path,
+        dest: &Path,
+        hasher: blake3::Hasher,
+    ) -> anyhow::Result<(String, u64)> {
+        let mut reader = HashingReader {
+            inner: File::open(src)?,
+            hasher,
+        };
+        let mut out = File::create(dest)?;
+        self.encode(&mut reader, &mut out)?;
+        Ok((reader.hasher.finalize().to_string(), out.metadata()?.len()))
+    }
+
+    /// Compresses content which is already in memory, e.g. a file which is packed.
+    pub fn compress_bytes(&self, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.encode(content, &mut out)?;
+        Ok(out)
+    }
+
+    fn encode<R: Read, W: Write>(&self, mut reader: R, out: W) -> anyhow::Result<()> {
+        match self {
+            Codec::Zstd => zstd::stream::copy_encode(&mut reader, out, ZSTD_LEVEL)?,
+            Codec::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(out, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                std::io::copy(&mut reader, &mut writer)?;
+                writer.into_inner();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The codec `--compress auto` chooses for a file: zstd, unless its extension shows that it is
+/// already compressed.
+pub fn auto(path: &Path) -> Option<Codec> {
+    let compressed = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+    (!compressed).then_some(Codec::Zstd)
+}
+
+/// Decompresses an object's content as it streams in, hashing the result as it is written.
+pub enum Decoder<W: Write> {
+    Plain(HashingWriter<W>),
+    Zstd(zstd::stream::zio::Writer<HashingWriter<W>, zstd::stream::raw::Decoder<'static>>),
+    Brotli(Box<brotli::DecompressorWriter<HashingWriter<W>>>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(codec: Option<Codec>, inner: W, hasher: blake3::Hasher) -> anyhow::Result<Self> {
+        let writer = HashingWriter { inner, hasher };
+        Ok(match codec {
+            None => Decoder::Plain(writer),
+            Some(Codec::Zstd) => Decoder::Zstd(zstd::stream::zio::Writer::new(
+                writer,
+                zstd::stream::raw::Decoder::new()?,
+            )),
+            Some(Codec::Brotli) => {
+                Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(writer, 4096)))
+            }
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let res = match self {
+            Decoder::Plain(writer) => return writer.write_all(data).map_err(|err| err.into()),
+            Decoder::Zstd(decoder) => decoder.write_all(data),
+            Decoder::Brotli(decoder) => decoder.write_all(data),
+        };
+        res.map_err(|err| Error::Integrity(format!("could not decompress: {}", err)).into())
+    }
+
+    /// Finishes decompressing, returning the hash of the content and the inner writer. Fails if
+    /// the compressed content ends part way through.
+    pub fn finish(self) -> anyhow::Result<(String, W)> {
+        let truncated =
+            || Error::Integrity("could not decompress: the content is truncated".into());
+        let writer = match self {
+            Decoder::Plain(writer) => writer,
+            Decoder::Zstd(mut decoder) => {
+                // unlike flushing, finishing checks that the last frame is complete
+                decoder.finish().map_err(|_| truncated())?;
+                decoder.into_inner().0
+            }
+            Decoder::Brotli(decoder) => decoder.into_inner().map_err(|_| truncated())?,
+        };
+        Ok((writer.hasher.finalize().to_string(), writer.inner))
+    }
+}
+
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content() -> Vec<u8> {
+        (0..200_000u32)
+            .flat_map(|i| (i % 1000).to_le_bytes())
+            .collect()
+    }
+
+    fn decode(codec: Option<Codec>, compressed: &[u8]) -> anyhow::Result<(String, Vec<u8>)> {
+        let mut decoder = Decoder::new(codec, Vec::new(), blake3::Hasher::new())?;
+        // written in uneven chunks, as the content streams in
+        for chunk in compressed.chunks(1000) {
+            decoder.write(chunk)?;
+        }
+        decoder.finish()
+    }
+
+    fn is_integrity(result: anyhow::Result<(String, Vec<u8>)>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::Integrity(_))
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        for codec in [None, Some(Codec::Zstd), Some(Codec::Brotli)] {
+            for content in [Vec::new(), b"a".to_vec(), content()] {
+                let compressed = match codec {
+                    Some(codec) => codec.compress_bytes(&content).unwrap(),
+                    None => content.clone(),
+                };
+                let (hash, decoded) = decode(codec, &compressed).unwrap();
+                assert_eq!(decoded, content, "{:?}", codec);
+                assert_eq!(hash, blake3::hash(&content).to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn compress_file_hashes_its_content() {
+        let dir = std::env::temp_dir().join(format!("s3b-compress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("content.txt");
+        let dest = dir.join("content.txt.zst");
+        let content = content();
+        std::fs::write(&src, &content).unwrap();
+        let (hash, size) = Codec::Zstd
+            .compress(&src, &dest, blake3::Hasher::new())
+            .unwrap();
+        assert_eq!(hash, blake3::hash(&content).to_string());
+        let compressed = std::fs::read(&dest).unwrap();
+        assert_eq!(size, compressed.len() as u64);
+        assert!(size < content.len() as u64);
+        assert_eq!(decode(Some(Codec::Zstd), &compressed).unwrap().1, content);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_content_fails() {
+        for codec in [Codec::Zstd, Codec::Brotli] {
+            let compressed = codec.compress_bytes(&content()).unwrap();
+            let truncated = &compressed[..compressed.len() - 10];
+            assert!(is_integrity(decode(Some(codec), truncated)), "{:?}", codec);
+            assert!(is_integrity(decode(Some(codec), &[])), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn corrupt_content_fails() {
+        for codec in [Codec::Zstd, Codec::Brotli] {
+            assert!(
+                is_integrity(decode(Some(codec), b"this is not compressed at all")),
+                "{:?}",
+                codec
+            );
+        }
+    }
+
+    #[test]
+    fn auto_skips_compressed_formats() {
+        assert_eq!(auto(Path::new("notes.txt")), Some(Codec::Zstd));
+        assert_eq!(auto(Path::new("Makefile")), Some(Codec::Zstd));
+        assert_eq!(auto(Path::new("photo.jpg")), None);
+        assert_eq!(auto(Path::new("PHOTO.JPG")), None);
+        assert_eq!(auto(Path::new("dir.zip/archive.tar.gz")), None);
+        assert_eq!(auto(Path::new("archive.gz/notes.txt")), Some(Codec::Zstd));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::{Error, Target};
+
+/// The name of the configuration file, looked for in the current directory and in
+/// `$XDG_CONFIG_HOME/s3b/` (by default `~/.config/s3b/`).
+const CONFIG_FILE: &str = "s3b.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Named targets and backup sets, so that commands don't have to repeat their options. Entries
+/// in the project's `s3b.toml` replace those of the same name in the user's.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The target of commands which are given no `--bucket`, `--target` or backup set
+    default_target: Option<String>,
+    #[serde(default)]
+    targets: BTreeMap<String, TargetConfig>,
+    #[serde(default)]
+    sets: BTreeMap<String, BackupSet>,
+}
+
+/// A named target, given as either `bucket` or `url`, with the options to connect to it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    bucket: Option<String>,
+    url: Option<String>,
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub profile: Option<String>,
+    /// Prepended to every key planned for this target, unless `--prefix` is given
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub path_style: bool,
+    #[serde(default)]
+    pub allow_http: bool,
+    /// The storage class of objects uploaded to this target, unless the plan chooses another
+    pub storage_class: Option<String>,
+    /// Server-side encryption, `aes256` or `kms`
+    pub sse: Option<String>,
+    pub kms_key_id: Option<String>,
+    /// A file holding the SSE-C key; relative paths are relative to the configuration file
+    pub sse_c_key: Option<PathBuf>,
+    /// A file holding the key for client-side encryption; relative like `sse_c_key`
+    pub key_file: Option<PathBuf>,
+    /// Derive the key for client-side encryption from a passphrase instead
+    #[serde(default)]
+    pub passphrase: bool,
+    #[serde(default)]
+    pub keyed_hashes: bool,
+    /// The transfer rate of `push` and `restore`, unless `--limit-rate` is given
+    pub limit_rate: Option<String>,
+    /// Daily windows with their own rate, unless `--limit-schedule` is given
+    #[serde(default)]
+    pub limit_schedule: Vec<String>,
+}
+
+/// A named directory to back up, with its filters and the target it is backed up to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupSet {
+    pub root: PathBuf,
+    /// The name of a target; defaults to `default_target`
+    target: Option<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The source label recorded with each entry, unless `--source` is given
+    pub source: Option<String>,
+    /// Storage classes as `CLASS` or `PATTERN=CLASS`, unless `--storage-class` is given
+    #[serde(default)]
+    pub storage_class: Vec<String>,
+    /// How to compress files, unless `--compress` is given
+    pub compress: Option<String>,
+    /// Files smaller than this size are packed, unless `--pack` is given
+    pub pack: Option<String>,
+}
+
+impl Config {
+    /// Returns the configuration, reading it the first time it is needed.
+    pub fn get() -> anyhow::Result<&'static Config> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let mut config = Config::default();
+        let user_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(dir) = user_dir {
+            config.merge(Config::read(&dir.join("s3b").join(CONFIG_FILE))?);
+        }
+        config.merge(Config::read(Path::new(CONFIG_FILE))?);
+        config.validate()?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    /// Reads a configuration file, which is empty if the file does not exist. Relative set roots
+    /// and key files are resolved against the directory of the file.
+    fn read(path: &Path) -> anyhow::Result<Config> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(
+                    Error::Config(format!("could not read {}: {}", path.display(), err)).into(),
+                )
+            }
+        };
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|err| Error::Config(format!("could not parse {}: {}", path.display(), err)))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let resolve = |path: &Path| match expand_home(path) {
+            path if path.is_relative() => dir.join(path),
+            path => path,
+        };
+        for set in config.sets.values_mut() {
+            set.root = resolve(&set.root);
+        }
+        for target in config.targets.values_mut() {
+            target.sse_c_key = target.sse_c_key.as_deref().map(resolve);
+            target.key_file = target.key_file.as_deref().map(resolve);
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Config) {
+        if other.default_target.is_some() {
+            self.default_target = other.default_target;
+        }
+        self.targets.extend(other.targets);
+        self.sets.extend(other.sets);
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, target) in &self.targets {
+            target
+                .target()
+                .map_err(|err| err.context(format!("target '{}' in {}", name, CONFIG_FILE)))?;
+        }
+        let set_targets = self.sets.values().filter_map(|set| set.target.as_ref());
+        for name in self.default_target.iter().chain(set_targets) {
+            if !self.targets.contains_key(name) {
+                return Err(Error::Config(format!(
+                    "no target named '{}' in {}",
+                    name, CONFIG_FILE
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up a named target.
+    pub fn target(&self, name: &str) -> anyhow::Result<&TargetConfig> {
+        self.targets.get(name).ok_or_else(|| {
+            Error::Config(format!("no target named '{}' in {}", name, CONFIG_FILE)).into()
+        })
+    }
+
+    /// Looks up the backup set given as the `[SET]` argument of the command, if it has one.
+    pub fn selected_set(matches: &ArgMatches) -> anyhow::Result<Option<&'static BackupSet>> {
+        match matches.try_get_one::<String>("set").ok().flatten() {
+            Some(name) => match Config::get()?.sets.get(name) {
+                Some(set) => Ok(Some(set)),
+                None => Err(Error::Config(format!(
+                    "no backup set named '{}' in {}",
+                    name, CONFIG_FILE
+                ))
+                .into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the name of the configured target which the command applies to: the one named by
+    /// `--target`, or else that of its backup set, or else the default target. Returns None if
+    /// the command was given a bucket or a target URL.
+    pub fn target_name(matches: &ArgMatches) -> anyhow::Result<Option<String>> {
+        if matches
+            .try_get_one::<String>("bucket")
+            .ok()
+            .flatten()
+            .is_some()
+        {
+            return Ok(None);
+        }
+        if let Some(target) = matches.try_get_one::<String>("target").ok().flatten() {
+            return Ok(if target.contains("://") {
+                None
+            } else {
+                Some(target.clone())
+            });
+        }
+        match Config::selected_set(matches)?.and_then(|set| set.target.clone()) {
+            Some(name) => Ok(Some(name)),
+            None => Ok(Config::get()?.default_target.clone()),
+        }
+    }
+
+    /// Returns the configured target which the command applies to; see [`Config::target_name`].
+    pub fn selected_target(matches: &ArgMatches) -> anyhow::Result<Option<&'static TargetConfig>> {
+        match Config::target_name(matches)? {
+            Some(name) => Config::get()?.target(&name).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl TargetConfig {
+    pub fn target(&self) -> anyhow::Result<Target> {
+        match (&self.bucket, &self.url) {
+            (Some(bucket), None) => Ok(Target::S3(bucket.clone())),
+            (None, Some(url)) => Target::parse(url),
+            _ => Err(Error::Config("expected exactly one of `bucket` and `url`".into()).into()),
+        }
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
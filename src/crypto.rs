@@ -0,0 +1,544 @@
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Records how the key of an encrypted target is derived, so that it can be checked before any
+/// object is read. Stored unencrypted, and the only object whose presence marks a target as
+/// encrypted.
+pub const KEY_OBJECT: &str = "_s3b_key.json";
+/// The passphrase is read from this variable if it is set, instead of being prompted for.
+pub const PASSPHRASE_VAR: &str = "S3B_PASSPHRASE";
+/// Objects are sealed in blocks of this many bytes, so that they can be decrypted as they stream.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+const MAGIC: &[u8; 8] = b"S3BENC\x00\x01";
+const OBJECT_NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + OBJECT_NONCE_LEN;
+const TAG_LEN: usize = 16;
+const SEALED_BLOCK: usize = BLOCK_SIZE as usize + TAG_LEN;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// The size of an object of `len` bytes once it is encrypted.
+pub fn sealed_len(len: u64) -> u64 {
+    HEADER_LEN as u64 + len + TAG_LEN as u64 * len.div_ceil(BLOCK_SIZE).max(1)
+}
+
+/// Where the key of an encrypted target comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// A file holding a 256-bit key
+    File(PathBuf),
+    /// A passphrase, from `S3B_PASSPHRASE` or else prompted for
+    Passphrase,
+}
+
+/// The contents of [`KEY_OBJECT`].
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyRecord {
+    version: u32,
+    /// `pbkdf2-hmac-sha256` for a passphrase, or `none` for a key file
+    kdf: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iterations: Option<u32>,
+    /// Whether the hashes in the database are keyed, rather than plain BLAKE3 hashes
+    keyed_hashes: bool,
+    /// Derived from the key, to tell a wrong key apart from corrupt objects
+    check: String,
+}
+
+/// The keys of an encrypted target, all derived from its master key.
+pub struct Keys {
+    master: [u8; 32],
+    hash: Option<[u8; 32]>,
+    names: [u8; 32],
+}
+
+impl Keys {
+    /// Sets up encryption with a new key record, returning the record to store in
+    /// [`KEY_OBJECT`] along with the keys.
+    pub fn create(source: &KeySource, keyed_hashes: bool) -> anyhow::Result<(Vec<u8>, Keys)> {
+        let (master, kdf, salt, iterations) = match source {
+            KeySource::File(path) => (read_key(path, "encryption")?, "none", None, None),
+            KeySource::Passphrase => {
+                let mut salt = [0u8; 16];
+                random(&mut salt)?;
+                let master = stretch(&passphrase(true)?, &salt, PBKDF2_ITERATIONS);
+                (
+                    master,
+                    "pbkdf2-hmac-sha256",
+                    Some(salt),
+                    Some(PBKDF2_ITERATIONS),
+                )
+            }
+        };
+        let record = KeyRecord {
+            version: 1,
+            kdf: kdf.into(),
+            salt: salt.map(|salt| BASE64_STANDARD.encode(salt)),
+            iterations,
+            keyed_hashes,
+            check: check_value(&master),
+        };
+        Ok((
+            serde_json::to_vec_pretty(&record)?,
+            Keys::derive(master, keyed_hashes),
+        ))
+    }
+
+    /// Derives the keys from the source, checking them against the stored key record.
+    /// `keyed_hashes` is only checked for agreement, since the record decides it.
+    pub fn unlock(record: &[u8], source: &KeySource, keyed_hashes: bool) -> anyhow::Result<Keys> {
+        let record: KeyRecord = serde_json::from_slice(record)
+            .map_err(|err| Error::Integrity(format!("could not decode {}: {}", KEY_OBJECT, err)))?;
+        if record.version != 1 {
+            return Err(Error::Config(format!(
+                "{} has version {}, which this version of s3b can't read",
+                KEY_OBJECT, record.version
+            ))
+            .into());
+        }
+        let master = match (record.kdf.as_str(), source) {
+            ("none", KeySource::File(path)) => read_key(path, "encryption")?,
+            ("pbkdf2-hmac-sha256", KeySource::Passphrase) => {
+                let salt = record
+                    .salt
+                    .as_deref()
+                    .and_then(|salt| BASE64_STANDARD.decode(salt).ok())
+                    .ok_or_else(|| Error::Integrity(format!("{} has no valid salt", KEY_OBJECT)))?;
+                let iterations = record.iterations.unwrap_or(PBKDF2_ITERATIONS);
+                stretch(&passphrase(false)?, &salt, iterations)
+            }
+            ("none", KeySource::Passphrase) => {
+                return Err(Error::Config(
+                    "the target is encrypted with a key file; pass --key-file".into(),
+                )
+                .into())
+            }
+            ("pbkdf2-hmac-sha256", KeySource::File(_)) => {
+                return Err(Error::Config(
+                    "the target is encrypted with a passphrase; pass --passphrase".into(),
+                )
+                .into())
+            }
+            (kdf, _) => {
+                return Err(Error::Config(format!(
+                    "{} uses unknown key derivation '{}'",
+                    KEY_OBJECT, kdf
+                ))
+                .into())
+            }
+        };
+        if check_value(&master) != record.check {
+            return Err(Error::Auth("wrong encryption key or passphrase".into()).into());
+        }
+        if keyed_hashes && !record.keyed_hashes {
+            return Err(Error::Config(
+                "the target was set up without keyed hashes, which can't be changed".into(),
+            )
+            .into());
+        }
+        Ok(Keys::derive(master, record.keyed_hashes))
+    }
+
+    fn derive(master: [u8; 32], keyed_hashes: bool) -> Keys {
+        Keys {
+            master,
+            hash: keyed_hashes.then(|| blake3::derive_key("s3b 2024 content hash", &master)),
+            names: blake3::derive_key("s3b 2024 object name", &master),
+        }
+    }
+
+    /// The key which hashes of content are keyed with, if hashes are keyed.
+    pub fn hash_key(&self) -> Option<&[u8; 32]> {
+        self.hash.as_ref()
+    }
+
+    /// The name an object is stored under, which reveals nothing about its key.
+    pub fn object_name(&self, key: &str) -> String {
+        blake3::keyed_hash(&self.names, key.as_bytes()).to_string()
+    }
+
+    /// Starts encrypting a new object, with a random nonce.
+    pub fn sealer(&self) -> anyhow::Result<Sealer> {
+        let mut nonce = [0u8; OBJECT_NONCE_LEN];
+        random(&mut nonce)?;
+        Ok(self.sealer_with_nonce(nonce))
+    }
+
+    /// Continues encrypting an object, e.g. the remaining parts of a multipart upload.
+    pub fn sealer_with_nonce(&self, nonce: [u8; OBJECT_NONCE_LEN]) -> Sealer {
+        Sealer {
+            key: self.object_key(&nonce),
+            nonce,
+        }
+    }
+
+    pub fn decryptor(&self) -> Decryptor<'_> {
+        Decryptor {
+            keys: self,
+            key: None,
+            buf: Vec::new(),
+            block: 0,
+        }
+    }
+
+    /// Each object is sealed with its own key, derived from the master key and the object's
+    /// nonce, so that random nonces can't collide between objects.
+    fn object_key(&self, nonce: &[u8; OBJECT_NONCE_LEN]) -> LessSafeKey {
+        let mut material = [0u8; 32 + OBJECT_NONCE_LEN];
+        material[..32].copy_from_slice(&self.master);
+        material[32..].copy_from_slice(nonce);
+        let key = blake3::derive_key("s3b 2024 object key", &material);
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+    }
+}
+
+/// Encrypts the blocks of one object. An object is a header holding a magic number and the
+/// object's 192-bit nonce, followed by its blocks, each sealed with ChaCha20-Poly1305 under a key
+/// derived with BLAKE3 from the master key and that nonce. This is not XChaCha20-Poly1305, which
+/// derives the key with HChaCha20, but likewise lets nonces be random. The nonce of each block is
+/// its index, flagged in the last byte if it is the final block, so that blocks can't be
+/// reordered, dropped or truncated without failing to decrypt.
+pub struct Sealer {
+    key: LessSafeKey,
+    nonce: [u8; OBJECT_NONCE_LEN],
+}
+
+impl Sealer {
+    pub fn nonce(&self) -> [u8; OBJECT_NONCE_LEN] {
+        self.nonce
+    }
+
+    /// Encrypts a whole object.
+    pub fn seal_object(&self, plaintext: &[u8]) -> Vec<u8> {
+        self.seal(plaintext, 0, true)
+    }
+
+    /// Encrypts part of an object which starts at block `first_block`, and must therefore start
+    /// at a multiple of [`BLOCK_SIZE`]. The header is prepended to the first part.
+    pub fn seal(&self, plaintext: &[u8], first_block: u64, last: bool) -> Vec<u8> {
+        let num_blocks = plaintext.len().div_ceil(BLOCK_SIZE as usize).max(1);
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + num_blocks * TAG_LEN);
+        if first_block == 0 {
+            sealed.extend_from_slice(MAGIC);
+            sealed.extend_from_slice(&self.nonce);
+        }
+        for idx in 0..num_blocks {
+            let start = idx * BLOCK_SIZE as usize;
+            let end = (start + BLOCK_SIZE as usize).min(plaintext.len());
+            let mut block = plaintext[start..end].to_vec();
+            let nonce = block_nonce(first_block + idx as u64, last && idx == num_blocks - 1);
+            self.key
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut block)
+                .expect("block is too large to seal");
+            sealed.extend_from_slice(&block);
+        }
+        sealed
+    }
+}
+
+/// Decrypts an object as it streams in. The last whole block is held back until more arrives,
+/// since only then is it known not to be the final block.
+pub struct Decryptor<'a> {
+    keys: &'a Keys,
+    /// Set once the header has been read
+    key: Option<LessSafeKey>,
+    buf: Vec<u8>,
+    block: u64,
+}
+
+impl Decryptor<'_> {
+    /// Decrypts what it can of the object so far.
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        if self.key.is_none() {
+            if self.buf.len() < HEADER_LEN {
+                return Ok(Vec::new());
+            }
+            if &self.buf[..MAGIC.len()] != MAGIC {
+                return Err(Error::Integrity("object is not encrypted by s3b".into()).into());
+            }
+            let nonce = self.buf[MAGIC.len()..HEADER_LEN].try_into().unwrap();
+            self.key = Some(self.keys.object_key(&nonce));
+            self.buf.drain(..HEADER_LEN);
+        }
+        let mut plaintext = Vec::new();
+        let mut offset = 0;
+        while self.buf.len() - offset > SEALED_BLOCK {
+            let block = &mut self.buf[offset..offset + SEALED_BLOCK];
+            plaintext.extend_from_slice(open(
+                self.key.as_ref().unwrap(),
+                self.block,
+                false,
+                block,
+            )?);
+            self.block += 1;
+            offset += SEALED_BLOCK;
+        }
+        self.buf.drain(..offset);
+        Ok(plaintext)
+    }
+
+    /// Decrypts the final block, failing if the object was truncated.
+    pub fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        match &self.key {
+            Some(key) if self.buf.len() >= TAG_LEN => {
+                Ok(open(key, self.block, true, &mut self.buf)?.to_vec())
+            }
+            _ => Err(Error::Integrity("encrypted object is truncated".into()).into()),
+        }
+    }
+}
+
+/// Decrypts a whole object.
+pub fn decrypt(keys: &Keys, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decryptor = keys.decryptor();
+    let mut plaintext = decryptor.update(sealed)?;
+    plaintext.extend(decryptor.finish()?);
+    Ok(plaintext)
+}
+
+fn open<'a>(
+    key: &LessSafeKey,
+    block: u64,
+    last: bool,
+    sealed: &'a mut [u8],
+) -> anyhow::Result<&'a [u8]> {
+    key.open_in_place(block_nonce(block, last), Aad::empty(), sealed)
+        .map(|plaintext| &*plaintext)
+        .map_err(|_| {
+            Error::Integrity(format!(
+                "block {} failed to decrypt; the object is corrupt",
+                block
+            ))
+            .into()
+        })
+}
+
+fn block_nonce(block: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&block.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn check_value(master: &[u8; 32]) -> String {
+    blake3::Hash::from(blake3::derive_key("s3b 2024 key check", master)).to_string()
+}
+
+fn stretch(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut master = [0u8; 32];
+    let iterations = NonZeroU32::new(iterations.max(1)).unwrap();
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut master,
+    );
+    master
+}
+
+/// Reads the passphrase from `S3B_PASSPHRASE`, or else prompts for it, twice if it is new.
+fn passphrase(new: bool) -> anyhow::Result<String> {
+    let passphrase = match std::env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => match new {
+            true => inquire::Password::new("New passphrase:").prompt()?,
+            false => inquire::Password::new("Passphrase:")
+                .without_confirmation()
+                .prompt()?,
+        },
+    };
+    if passphrase.is_empty() {
+        return Err(Error::Config("the passphrase is empty".into()).into());
+    }
+    Ok(passphrase)
+}
+
+fn random(buf: &mut [u8]) -> anyhow::Result<()> {
+    SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| anyhow::anyhow!("could not generate random bytes"))
+}
+
+/// Reads a 256-bit key, either 32 raw bytes or their base64 encoding.
+pub fn read_key(path: &Path, kind: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = std::fs::read(path).map_err(|err| {
+        Error::Config(format!(
+            "could not read {} key {}: {}",
+            kind,
+            path.display(),
+            err
+        ))
+    })?;
+    let key = match bytes.len() {
+        32 => bytes,
+        _ => BASE64_STANDARD
+            .decode(bytes.trim_ascii())
+            .unwrap_or_default(),
+    };
+    key.try_into().map_err(|_| {
+        Error::Config(format!(
+            "{} must hold a 256-bit {} key, as 32 raw bytes or base64",
+            path.display(),
+            kind
+        ))
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    fn keys(byte: u8) -> Keys {
+        Keys::derive([byte; 32], false)
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn is_integrity(result: anyhow::Result<Vec<u8>>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::Integrity(_))
+        )
+    }
+
+    #[test]
+    fn sealed_len_matches_sealed_object() {
+        let keys = keys(1);
+        for len in [0, 1, BLOCK - 1, BLOCK, BLOCK + 1, 3 * BLOCK + 5] {
+            let sealed = keys.sealer().unwrap().seal_object(&content(len));
+            assert_eq!(
+                sealed.len() as u64,
+                sealed_len(len as u64),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = keys(1);
+        for len in [0, 1, BLOCK - 1, BLOCK, BLOCK + 1, 3 * BLOCK + 5] {
+            let plaintext = content(len);
+            let sealed = keys.sealer().unwrap().seal_object(&plaintext);
+            assert_eq!(
+                decrypt(&keys, &sealed).unwrap(),
+                plaintext,
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_in_uneven_chunks() {
+        let keys = keys(1);
+        let plaintext = content(2 * BLOCK);
+        let sealed = keys.sealer().unwrap().seal_object(&plaintext);
+        let mut decryptor = keys.decryptor();
+        let mut decrypted = Vec::new();
+        for chunk in sealed.chunks(7919) {
+            decrypted.extend(decryptor.update(chunk).unwrap());
+        }
+        decrypted.extend(decryptor.finish().unwrap());
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let keys = keys(1);
+        let plaintext = content(5 * BLOCK + 17);
+        let sealer = keys.sealer().unwrap();
+        // a later part is sealed by a sealer rebuilt from the nonce, as a resumed upload does
+        let resumed = keys.sealer_with_nonce(sealer.nonce());
+        let part_size = 2 * BLOCK;
+        let parts = plaintext.chunks(part_size).collect::<Vec<_>>();
+        let mut sealed = Vec::new();
+        for (idx, part) in parts.iter().enumerate() {
+            let sealer = if idx == 0 { &sealer } else { &resumed };
+            let first_block = (idx * part_size / BLOCK) as u64;
+            sealed.extend(sealer.seal(part, first_block, idx == parts.len() - 1));
+        }
+        assert_eq!(sealed.len() as u64, sealed_len(plaintext.len() as u64));
+        assert_eq!(decrypt(&keys, &sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn truncation_fails() {
+        let keys = keys(1);
+        let sealed = keys.sealer().unwrap().seal_object(&content(3 * BLOCK + 5));
+        // within the final block
+        assert!(is_integrity(decrypt(&keys, &sealed[..sealed.len() - 1])));
+        // the final block dropped, so that the object ends on a whole block
+        assert!(is_integrity(decrypt(
+            &keys,
+            &sealed[..HEADER_LEN + 3 * SEALED_BLOCK]
+        )));
+        // nothing but the header
+        assert!(is_integrity(decrypt(&keys, &sealed[..HEADER_LEN])));
+        assert!(is_integrity(decrypt(&keys, &sealed[..HEADER_LEN - 1])));
+    }
+
+    #[test]
+    fn reordered_blocks_fail() {
+        let keys = keys(1);
+        let mut sealed = keys.sealer().unwrap().seal_object(&content(3 * BLOCK));
+        let (first, second) = sealed[HEADER_LEN..].split_at_mut(SEALED_BLOCK);
+        first.swap_with_slice(&mut second[..SEALED_BLOCK]);
+        assert!(is_integrity(decrypt(&keys, &sealed)));
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let keys = keys(1);
+        let mut sealed = keys.sealer().unwrap().seal_object(&content(BLOCK + 5));
+        sealed[HEADER_LEN + 100] ^= 1;
+        assert!(is_integrity(decrypt(&keys, &sealed)));
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let sealed = keys(1).sealer().unwrap().seal_object(&content(100));
+        assert!(is_integrity(decrypt(&keys(2), &sealed)));
+    }
+
+    #[test]
+    fn unlock_checks_the_key() {
+        let dir = std::env::temp_dir().join(format!("s3b-crypto-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let right = dir.join("right.key");
+        let wrong = dir.join("wrong.key");
+        std::fs::write(&right, [1u8; 32]).unwrap();
+        std::fs::write(&wrong, BASE64_STANDARD.encode([2u8; 32])).unwrap();
+
+        let (record, keys) = Keys::create(&KeySource::File(right.clone()), true).unwrap();
+        let sealed = keys.sealer().unwrap().seal_object(b"content");
+        let unlocked = Keys::unlock(&record, &KeySource::File(right), false).unwrap();
+        assert_eq!(unlocked.hash_key(), keys.hash_key());
+        assert_eq!(decrypt(&unlocked, &sealed).unwrap(), b"content");
+        let err = Keys::unlock(&record, &KeySource::File(wrong), false)
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Auth(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::process::ExitCode;
+
+/// Errors which s3b knows how to explain; each kind maps to a distinct process exit code so that
+/// scripts can tell failures apart without parsing output.
+#[derive(Debug)]
+pub enum Error {
+    /// Missing or invalid configuration, e.g. an unset region or an unreadable plan file
+    Config(String),
+    /// Credentials could not be loaded or were rejected
+    Auth(String),
+    /// The remote could not be reached or returned an unexpected error
+    Network(String),
+    /// The bucket, object or plan file does not exist
+    NotFound(String),
+    /// Local and remote state disagree in a way s3b will not resolve on its own
+    Conflict(String),
+    /// Data failed an integrity check, e.g. a corrupt plan file
+    Integrity(String),
+    /// Some, but not all, objects in a plan were uploaded
+    Partial(String),
+}
+
+impl Error {
+    /// A short name for the kind of error, as accepted by `--retry-on`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Auth(_) => "auth",
+            Error::Network(_) => "network",
+            Error::NotFound(_) => "not-found",
+            Error::Conflict(_) => "conflict",
+            Error::Integrity(_) => "integrity",
+            Error::Partial(_) => "partial",
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Error::Config(_) => 3,
+            Error::Auth(_) => 4,
+            Error::Network(_) => 5,
+            Error::NotFound(_) => 6,
+            Error::Conflict(_) => 7,
+            Error::Integrity(_) => 8,
+            Error::Partial(_) => 9,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "configuration error: {}", msg),
+            Error::Auth(msg) => write!(f, "authentication error: {}", msg),
+            Error::Network(msg) => write!(f, "network error: {}", msg),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Conflict(msg) => write!(f, "conflict: {}", msg),
+            Error::Integrity(msg) => write!(f, "integrity check failed: {}", msg),
+            Error::Partial(msg) => write!(f, "partial upload: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<object_store::Error> for Error {
+    fn from(err: object_store::Error) -> Self {
+        match err {
+            object_store::Error::NotFound { path, .. } => {
+                Error::NotFound(format!("no object at key '{}'", path))
+            }
+            object_store::Error::Unauthenticated { .. }
+            | object_store::Error::PermissionDenied { .. } => Error::Auth(err.to_string()),
+            object_store::Error::AlreadyExists { .. }
+            | object_store::Error::Precondition { .. } => Error::Conflict(err.to_string()),
+            object_store::Error::UnknownConfigurationKey { .. } => Error::Config(err.to_string()),
+            object_store::Error::Generic { .. } if err.to_string().contains("NoSuchBucket") => {
+                Error::NotFound("bucket does not exist".into())
+            }
+            object_store::Error::Generic { .. }
+                if err.to_string().contains("ContainerNotFound") =>
+            {
+                Error::NotFound("container does not exist".into())
+            }
+            object_store::Error::Generic { .. }
+                if err.to_string().contains("InvalidObjectState") =>
+            {
+                Error::Conflict(format!(
+                    "the object is archived; restore it with `s3b restore` first: {}",
+                    err
+                ))
+            }
+            object_store::Error::Generic { .. }
+                if err.to_string().contains("BadDigest")
+                    || err.to_string().contains("XAmzContentSHA256Mismatch") =>
+            {
+                Error::Integrity(format!(
+                    "the remote rejected the upload's checksum: {}",
+                    err
+                ))
+            }
+            _ => Error::Network(err.to_string()),
+        }
+    }
+}
+
+/// The result of a command which completed without error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    /// The command had no work to do, e.g. an empty plan
+    NothingToDo,
+    /// The command found differences between local and remote state, e.g. from `status`
+    Differences,
+    /// The command is waiting on the remote, e.g. for archived objects to be restored
+    Pending,
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Outcome::Done => ExitCode::SUCCESS,
+            Outcome::NothingToDo => ExitCode::from(20),
+            Outcome::Differences => ExitCode::from(21),
+            Outcome::Pending => ExitCode::from(22),
+        }
+    }
+}
+
+/// Returns the exit code for an error, using the outermost [`Error`] it was raised or wrapped with.
+pub fn exit_code(err: &anyhow::Error) -> ExitCode {
+    err.downcast_ref::<Error>()
+        .or_else(|| err.chain().find_map(|e| e.downcast_ref::<Error>()))
+        .map(Error::exit_code)
+        .unwrap_or(ExitCode::FAILURE)
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use object_store::{
+    multipart::{MultipartStore, PartId},
+    path::Path as ObjectPath,
+    MultipartId, PutPayload, PutResult,
+};
+use walkdir::WalkDir;
+
+use crate::s3::PendingUpload;
+
+/// Multipart uploads to a local directory, which object_store's `LocalFileSystem` cannot resume.
+/// An upload is staged next to its destination as `<file>#<upload id>`, with each part in
+/// `<file>#<upload id><part index>`; `LocalFileSystem` leaves both out of listings. Completing
+/// the upload appends the parts to the staging file and renames it into place.
+#[derive(Debug)]
+pub struct LocalMultipart {
+    root: PathBuf,
+}
+
+/// Upload IDs are always this many digits, so that they can be told apart from part files.
+const UPLOAD_ID_LEN: usize = 10;
+
+impl LocalMultipart {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn staging_path(&self, location: &ObjectPath, id: &str) -> PathBuf {
+        let mut path = self.root.join(location.as_ref()).into_os_string();
+        path.push(format!("#{}", id));
+        path.into()
+    }
+
+    fn part_path(&self, location: &ObjectPath, id: &str, part_idx: usize) -> PathBuf {
+        let mut path = self.staging_path(location, id).into_os_string();
+        path.push(format!("{:05}", part_idx));
+        path.into()
+    }
+
+    /// Lists the uploads under the root which have not been completed or aborted.
+    pub fn list_uploads(&self) -> anyhow::Result<Vec<PendingUpload>> {
+        let mut pending = Vec::new();
+        for entry in WalkDir::new(&self.root).min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&self.root)?;
+            let Some((key, id)) = relative.to_str().and_then(|p| p.rsplit_once('#')) else {
+                continue;
+            };
+            if id.len() != UPLOAD_ID_LEN || !id.chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            pending.push(PendingUpload {
+                key: key.to_string(),
+                upload_id: id.to_string(),
+                initiated: entry.metadata()?.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+        Ok(pending)
+    }
+}
+
+#[async_trait]
+impl MultipartStore for LocalMultipart {
+    async fn create_multipart(&self, location: &ObjectPath) -> object_store::Result<MultipartId> {
+        let id = format!("{:010}", rand::random::<u32>());
+        let path = self.staging_path(location, &id);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| io_error(&path, err))?;
+        }
+        File::create_new(&path).map_err(|err| io_error(&path, err))?;
+        Ok(id)
+    }
+
+    async fn put_part(
+        &self,
+        location: &ObjectPath,
+        id: &MultipartId,
+        part_idx: usize,
+        data: PutPayload,
+    ) -> object_store::Result<PartId> {
+        let staging = self.staging_path(location, id);
+        if !staging.is_file() {
+            return Err(io_error(&staging, std::io::ErrorKind::NotFound.into()));
+        }
+        let path = self.part_path(location, id, part_idx);
+        let mut file = File::create(&path).map_err(|err| io_error(&path, err))?;
+        for chunk in data.iter() {
+            file.write_all(chunk).map_err(|err| io_error(&path, err))?;
+        }
+        file.sync_all().map_err(|err| io_error(&path, err))?;
+        Ok(PartId {
+            content_id: part_idx.to_string(),
+        })
+    }
+
+    async fn complete_multipart(
+        &self,
+        location: &ObjectPath,
+        id: &MultipartId,
+        parts: Vec<PartId>,
+    ) -> object_store::Result<PutResult> {
+        let staging = self.staging_path(location, id);
+        let mut file = File::options()
+            .write(true)
+            .truncate(true)
+            .open(&staging)
+            .map_err(|err| io_error(&staging, err))?;
+        for part in &parts {
+            let part_idx =
+                part.content_id
+                    .parse::<usize>()
+                    .map_err(|err| object_store::Error::Generic {
+                        store: "LocalMultipart",
+                        source: Box::new(err),
+                    })?;
+            let path = self.part_path(location, id, part_idx);
+            let mut part_file = File::open(&path).map_err(|err| io_error(&path, err))?;
+            std::io::copy(&mut part_file, &mut file).map_err(|err| io_error(&path, err))?;
+        }
+        file.sync_all().map_err(|err| io_error(&staging, err))?;
+        let dest = self.root.join(location.as_ref());
+        std::fs::rename(&staging, &dest).map_err(|err| io_error(&dest, err))?;
+        remove_parts(&staging);
+        Ok(PutResult {
+            e_tag: None,
+            version: None,
+        })
+    }
+
+    async fn abort_multipart(
+        &self,
+        location: &ObjectPath,
+        id: &MultipartId,
+    ) -> object_store::Result<()> {
+        let staging = self.staging_path(location, id);
+        remove_parts(&staging);
+        std::fs::remove_file(&staging).map_err(|err| io_error(&staging, err))
+    }
+}
+
+/// Removes the part files of the upload staged at `staging`, ignoring any which can't be removed.
+fn remove_parts(staging: &Path) {
+    let (Some(dir), Some(name)) = (staging.parent(), staging.file_name()) else {
+        return;
+    };
+    let Some(name) = name.to_str() else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_name.len() > name.len() && file_name.starts_with(name) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> object_store::Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        object_store::Error::NotFound {
+            path: path.display().to_string(),
+            source: Box::new(err),
+        }
+    } else {
+        object_store::Error::Generic {
+            store: "LocalMultipart",
+            source: Box::new(err),
+        }
+    }
+}
@@ -1,42 +1,131 @@
 mod commands;
+mod compress;
+mod config;
+mod crypto;
+mod error;
+mod local;
+mod pack;
+mod retry;
 mod s3;
 mod sql;
+mod target;
+mod throttle;
+mod uploads;
 
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use clap::{arg, command};
+use clap::{arg, command, ArgAction, ArgGroup, ArgMatches, Command};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
 
-use s3::S3;
+use commands::plan::Change;
+use config::Config;
+use error::{Error, Outcome};
+use retry::RetryPolicy;
+use s3::{ClientConfig, MultipartConfig, S3};
 use sql::Sql;
+use target::Target;
+use throttle::RateLimiter;
 
-use commands::{drop::drop, find::find, info::info, plan::plan, push::push};
+use commands::{
+    diff::diff, drop::drop, find::find, gc::gc, info::info, plan::plan, push::push,
+    restore::restore, show_plan::show_plan, status::status, verify::verify,
+};
 
 #[tokio::main]
-async fn main() {
-    let matches = command!()
+async fn main() -> ExitCode {
+    match run(&cli().get_matches()).await {
+        Ok(outcome) => outcome.exit_code(),
+        Err(err) => {
+            eprintln!("{}", format!("ERROR: {:#}", err).red());
+            error::exit_code(&err)
+        }
+    }
+}
+
+fn cli() -> Command {
+    ClientConfig::args(RetryPolicy::args(command!()))
         .subcommand_required(true)
         .subcommand(
-            command!("find")
+            Target::args(command!("find"))
                 .about("Run an SQL SELECT query against the embedded database in the given bucket, using the specified WHERE clause")
-                .arg(arg!(--"bucket" <BUCKET>).required(true))
-                .arg(arg!(--"where" <QUERY>).required(true))
+                .arg(arg!(--"where" <QUERY>))
+                .arg(arg!(--"source" <LABEL> "Only find objects pushed with this source label"))
+                .arg(
+                    arg!(--"storage-class" <CLASS> "Only find objects uploaded with this storage class, e.g. DEEP_ARCHIVE")
+                        .value_parser(s3::check_storage_class),
+                )
+                .group(ArgGroup::new("query").args(["where", "source", "storage-class"]).multiple(true).required(true))
                 .arg(arg!(--"endpoint" <ENDPOINT>).required(false)),
         )
         .subcommand(
-            command!("info")
+            Target::args(command!("info"))
                 .about("Print information such as hash and origin path for the given key")
-                .arg(arg!(--"bucket" <BUCKET>).required(true))
                 .arg(arg!(--"key" <KEY>).required(true))
                 .arg(arg!(--"endpoint" <ENDPOINT>).required(false)),
         )
         .subcommand(
-            command!("plan")
+            Target::args(command!("plan"))
                 .about("Generates a plan file against the specified bucket for files in the current directory. Warnings will be shown for any existing objects having the same hash as a new file in the plan")
-                .arg(arg!(--"bucket" <BUCKET>).required(true))
+                .arg(arg!([SET] "A backup set from s3b.toml to plan, instead of the current directory").id("set"))
+                .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+                .arg(
+                    arg!(--"exclude" <EXCLUDE>)
+                        .value_delimiter(' ')
+                        .num_args(1..),
+                )
+                .arg(
+                    arg!(--"include" <INCLUDE>)
+                        .value_delimiter(' ')
+                        .num_args(1..),
+                )
+                .arg(arg!(--"prefix" <PREFIX> "A prefix for every key in the plan, e.g. machines/laptop/"))
+                .arg(arg!(--"source" <LABEL> "A label for where the files come from, recorded in the database"))
+                .arg(
+                    arg!(--"storage-class" <CLASS> "The S3 storage class of new objects, e.g. GLACIER_IR; given as PATTERN=CLASS, only for paths containing PATTERN")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"compress" <CODEC> "Compress files before uploading them with zstd or brotli; auto uses zstd except for files which are already compressed")
+                        .value_parser(["zstd", "brotli", "auto", "none"]),
+                )
+                .arg(
+                    arg!(--"pack" <SIZE> "Pack files smaller than SIZE, e.g. 64K, into shared pack objects, to save requests for many small files; at most 8M")
+                        .value_parser(commands::plan::parse_pack_threshold),
+                )
+                .arg(
+                    arg!(--"plan-out" <FILE> "Where to write the plan file")
+                        .default_value(PLAN_FILE),
+                )
+                .arg(
+                    arg!(--"plan-format" <FORMAT> "How to encode the plan file")
+                        .value_parser(["binary", "json"])
+                        .default_value("binary"),
+                ),
+        )
+        .subcommand(
+            command!("show-plan")
+                .about("List the entries in a plan file, with the action push will take for each")
+                .arg(arg!([FILE] "The plan file to show; defaults to s3b_plan.bin in the current directory").id("file"))
+                .arg(
+                    arg!(--"action" <ACTION> "Only show entries with this action")
+                        .value_parser(["new", "update"]),
+                )
+                .arg(
+                    arg!(--"filter" <FILTER> "Only show entries whose key contains one of the filters")
+                        .value_delimiter(' ')
+                        .num_args(1..),
+                )
+                .arg(arg!(--"json" "Print the plan as JSON")),
+        )
+        .subcommand(
+            Target::args(command!("status"))
+                .about("Compare files in the current directory to the specified bucket without writing a plan. Exits with code 21 if there are differences")
+                .arg(arg!([SET] "A backup set from s3b.toml to compare, instead of the current directory").id("set"))
                 .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+                .arg(arg!(--"prefix" <PREFIX> "The prefix the files were planned with"))
                 .arg(
                     arg!(--"exclude" <EXCLUDE>)
                         .value_delimiter(' ')
@@ -49,52 +138,307 @@ async fn main() {
                 ),
         )
         .subcommand(
-            command!("push")
-            .about("If there is an s3b_plan.bin in the current directory, execute the plan and push any listed files to the bucket specified in the plan")
+            MultipartConfig::args(RateLimiter::args(command!("push")))
+            .about("Execute a plan and push any listed files to the bucket specified in the plan. Files are read from where they were planned, so push can be run from any directory")
             .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+            .arg(
+                arg!(--"plan" <FILE> "The plan file to push")
+                    .default_value(PLAN_FILE),
+            )
+            .arg(arg!(--"force" "Push even if files or the remote database changed since the plan was made"))
         )
         .subcommand(
-            command!("drop")
+            RateLimiter::args(Target::args(command!("restore")))
+            .about("Download objects from the bucket into a directory, first restoring any in an archive storage class such as GLACIER or DEEP_ARCHIVE. Exits with code 22 while restores are pending")
+            .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+            .arg(arg!(--"where" <QUERY> "Only restore objects matching this WHERE clause, as for find"))
+            .arg(arg!(--"path" <PATH> "Only restore the object at this key, or every object under it if it has a trailing slash (/)"))
+            .arg(
+                arg!(--"to" <DIR> "The directory to restore into; each object is written to its key under it")
+                    .default_value("."),
+            )
+            .arg(
+                arg!(--"tier" <TIER> "How quickly archived objects are restored: standard, bulk or expedited")
+                    .value_parser(commands::restore::parse_tier)
+                    .default_value("standard"),
+            )
+            .arg(
+                arg!(--"days" <DAYS> "How many days restored copies of archived objects are kept")
+                    .value_parser(clap::value_parser!(i32).range(1..))
+                    .default_value("7"),
+            )
+            .arg(arg!(--"force" "Overwrite files which already exist with different content"))
+            .arg(arg!(--"continue" "Download the objects whose restores are pending from an earlier restore").conflicts_with_all(["where", "path", "to", "tier", "days", "force", "destination"]))
+            .arg(arg!(--"wait" "Wait for pending restores, downloading objects as they become available"))
+            .arg(
+                arg!(--"poll" <MINUTES> "How often to check pending restores with --wait")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("15"),
+            )
+        )
+        .subcommand(
+            Target::args(command!("drop"))
             .about("Delete remote object(s) at the specified path. Deletes all objects under prefix if path has a trailing slash (/)")
-            .arg(arg!(--"bucket" <BUCKET>).required(true))
             .arg(arg!(--"path" <PATH>).required(true))
             .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
         )
-        .get_matches();
+        .subcommand(
+            Target::args(command!("verify"))
+            .about("Check that the objects in the given bucket match the embedded database")
+            .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+            .arg(arg!(--"deep" "Download and re-hash every object").conflicts_with("sample"))
+            .arg(
+                arg!(--"sample" <PERCENT> "Download and re-hash a random sample of objects, e.g. 5%")
+                    .value_parser(commands::verify::parse_percent),
+            )
+        )
+        .subcommand(
+            command!("diff")
+            .about("Compare the databases of two buckets, a bucket against a local directory, or two local directories, by key and by hash")
+            .arg(
+                arg!(--"bucket" <BUCKET> "A bucket to compare; pass twice to compare two buckets")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                arg!(--"target" <URL> "A target to compare, e.g. file:///mnt/backup, or the name of a target in s3b.toml; after any buckets")
+                    .value_parser(Target::check_arg)
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                arg!(--"dir" <DIR> "A local directory to compare against the bucket; pass twice to compare two directories")
+                    .action(ArgAction::Append),
+            )
+            .group(ArgGroup::new("destination").args(["bucket", "target", "dir"]).multiple(true).required(true))
+            .arg(arg!(--"prefix" <PREFIX> "A prefix for the keys of files in the local directories").requires("dir"))
+            .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+            .arg(arg!(--"json" "Print the differences as JSON"))
+        )
+        .subcommand(
+            command!("gc")
+            .about("Clean up leftover state in the bucket")
+            .subcommand_required(true)
+            .subcommand(
+                Target::args(command!("multipart"))
+                .about("Abort multipart uploads under a prefix which were never completed, except those which pushing the plan again can resume")
+                .arg(arg!(--"endpoint" <ENDPOINT>).required(false))
+                .arg(
+                    arg!(--"plan" <FILE> "The plan whose interrupted uploads are left alone")
+                        .default_value(PLAN_FILE),
+                )
+                .arg(arg!(--"prefix" <PREFIX> "Only abort uploads of keys under this prefix; defaults to the prefix of the configured target"))
+                .arg(arg!(--"all" "Abort every incomplete upload in the bucket, including those of other hosts and tools").conflicts_with("prefix"))
+                .arg(
+                    arg!(--"older-than" <HOURS> "Only abort uploads started at least this many hours ago")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("24"),
+                )
+            )
+        )
+}
 
-    if let Err(err) = match matches.subcommand() {
+async fn run(matches: &ArgMatches) -> anyhow::Result<Outcome> {
+    match matches.subcommand() {
+        Some(("diff", subcommand)) => diff(subcommand).await,
         Some(("drop", subcommand)) => drop(subcommand).await,
         Some(("find", subcommand)) => find(subcommand).await,
+        Some(("gc", subcommand)) => gc(subcommand).await,
         Some(("info", subcommand)) => info(subcommand).await,
         Some(("plan", subcommand)) => plan(subcommand).await,
         Some(("push", subcommand)) => push(subcommand).await,
+        Some(("restore", subcommand)) => restore(subcommand).await,
+        Some(("show-plan", subcommand)) => show_plan(subcommand).await,
+        Some(("status", subcommand)) => status(subcommand).await,
+        Some(("verify", subcommand)) => verify(subcommand).await,
         _ => unreachable!("skipper's drunk!"),
-    } {
-        println!("{}", format!("ERROR: {:?}", err).red());
     }
 }
 
+/// Parses a size such as `500K` or `5M` into bytes. Suffixes are powers of 1024.
+pub fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1024),
+        Some((i, 'm' | 'M')) => (&size[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&size[..i], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    match digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+    {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(anyhow::anyhow!(
+            "invalid size '{}'; expected e.g. 500K or 5M",
+            size
+        )),
+    }
+}
+
+/// The default location of the plan file, in the current directory
+const PLAN_FILE: &str = "s3b_plan.bin";
+/// Identifies a binary plan file; JSON plan files are identified by `PlanHeader::format`
+const PLAN_MAGIC: &[u8; 8] = b"S3BPLAN\0";
+const PLAN_FORMAT: &str = "s3b-plan";
+/// Incremented whenever `Plan` or `PlanEntry` change in a way older versions cannot read
+const PLAN_FORMAT_VERSION: u32 = 8;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Plan {
-    bucket_name: String,
+    header: PlanHeader,
+    target: Target,
+    /// The name of the target in `s3b.toml`, if the plan was made against one, so that `push`
+    /// connects to it with the same options
+    target_name: Option<String>,
     base_path: PathBuf,
     entries: Vec<PlanEntry>, // TODO this might be more efficient as a map
+    /// The ETag of the remote database when the plan was made, to detect changes before pushing
+    db_etag: Option<String>,
+    /// Prepended to the key of every entry, e.g. `machines/laptop/`
+    prefix: String,
+    /// A label for where the files came from, e.g. a host or disk, recorded with each entry
+    source: Option<String>,
+    /// How the plan was read, so that it is rewritten the same way
+    #[serde(skip)]
+    encoding: PlanEncoding,
+}
+
+/// Describes the plan file itself, so that incompatible plans are rejected with a clear error.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanHeader {
+    format: String,
+    version: u32,
+    /// When the plan was created, as a UNIX timestamp
+    created: u64,
+    /// The version of s3b which created the plan
+    s3b_version: String,
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlanEncoding {
+    /// Brotli-compressed bincode, after a magic header and the format version
+    #[default]
+    Binary,
+    Json,
 }
 
 impl Plan {
-    fn read() -> Self {
-        let fin = std::fs::File::open("./s3b_plan.bin").unwrap();
-        let mut decompressor = brotli::Decompressor::new(fin, 4096);
+    fn new(
+        target: &Target,
+        base_path: PathBuf,
+        entries: Vec<PlanEntry>,
+        db_etag: Option<String>,
+        endpoint: Option<&str>,
+        encoding: PlanEncoding,
+    ) -> Self {
+        Plan {
+            header: PlanHeader {
+                format: PLAN_FORMAT.to_string(),
+                version: PLAN_FORMAT_VERSION,
+                created: chrono::Utc::now().timestamp() as u64,
+                s3b_version: clap::crate_version!().to_string(),
+                endpoint: endpoint.map(|s| s.to_string()),
+            },
+            target: target.clone(),
+            target_name: None,
+            base_path,
+            entries,
+            db_etag,
+            prefix: String::new(),
+            source: None,
+            encoding,
+        }
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let mut fin = match std::fs::File::open(path) {
+            Ok(fin) => fin,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::NotFound(format!(
+                    "no plan file at {}; run `s3b plan` first",
+                    path.display()
+                ))
+                .into())
+            }
+            Err(err) => {
+                return Err(
+                    Error::Config(format!("could not open {}: {}", path.display(), err)).into(),
+                )
+            }
+        };
         let mut buf: Vec<u8> = Vec::new();
-        decompressor.read_to_end(&mut buf).unwrap();
-        bincode::deserialize(&buf).unwrap()
+        fin.read_to_end(&mut buf)?;
+
+        if let Some(body) = buf.strip_prefix(PLAN_MAGIC) {
+            let (version, body) = body
+                .split_at_checked(4)
+                .ok_or_else(|| Error::Integrity(format!("{} is truncated", path.display())))?;
+            check_plan_version(path, u32::from_le_bytes(version.try_into()?))?;
+            let mut decompressor = brotli::Decompressor::new(body, 4096);
+            let mut buf: Vec<u8> = Vec::new();
+            decompressor.read_to_end(&mut buf).map_err(|err| {
+                Error::Integrity(format!("could not decompress {}: {}", path.display(), err))
+            })?;
+            bincode::deserialize(&buf).map_err(|err| {
+                Error::Integrity(format!("could not decode {}: {}", path.display(), err)).into()
+            })
+        } else if buf.trim_ascii_start().starts_with(b"{") {
+            let value: serde_json::Value = serde_json::from_slice(&buf).map_err(|err| {
+                Error::Integrity(format!("could not decode {}: {}", path.display(), err))
+            })?;
+            let header = &value["header"];
+            if header["format"] != PLAN_FORMAT {
+                return Err(Error::Integrity(format!(
+                    "{} is not an s3b plan file",
+                    path.display()
+                ))
+                .into());
+            }
+            let version = header["version"].as_u64().unwrap_or_default();
+            check_plan_version(path, version.try_into().unwrap_or(u32::MAX))?;
+            let mut plan: Plan = serde_json::from_value(value).map_err(|err| {
+                Error::Integrity(format!("could not decode {}: {}", path.display(), err))
+            })?;
+            plan.encoding = PlanEncoding::Json;
+            Ok(plan)
+        } else {
+            Err(Error::Integrity(format!(
+                "{} is not an s3b plan file, or was written by an older version of s3b; run `s3b plan` again",
+                path.display()
+            ))
+            .into())
+        }
     }
 
-    fn write(&self) {
-        let plan_bytes = bincode::serialize(&self).unwrap();
-        let fout = std::fs::File::create("./s3b_plan.bin").unwrap();
-        let mut compressor = brotli::CompressorWriter::new(fout, 4096, 11, 22);
-        compressor.write_all(&plan_bytes).unwrap();
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut fout = std::fs::File::create(path)?;
+        match self.encoding {
+            PlanEncoding::Binary => {
+                let plan_bytes = bincode::serialize(&self)?;
+                fout.write_all(PLAN_MAGIC)?;
+                fout.write_all(&self.header.version.to_le_bytes())?;
+                let mut compressor = brotli::CompressorWriter::new(fout, 4096, 11, 22);
+                compressor.write_all(&plan_bytes)?;
+            }
+            PlanEncoding::Json => serde_json::to_writer_pretty(fout, &self)?,
+        }
+        Ok(())
+    }
+}
+
+fn check_plan_version(path: &Path, version: u32) -> anyhow::Result<()> {
+    if version == PLAN_FORMAT_VERSION {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "{} has plan format version {}, but s3b {} only reads version {}; run `s3b plan` again with this version",
+            path.display(),
+            version,
+            clap::crate_version!(),
+            PLAN_FORMAT_VERSION
+        ))
+        .into())
     }
 }
 
@@ -104,4 +448,503 @@ pub struct PlanEntry {
     path: PathBuf,
     hash: String,
     modified: u64,
+    size: u64,
+    /// Why the entry is in the plan; either new, or selected from the conflicting objects
+    change: Change,
+    /// The storage class to upload the object with; the target's default if None
+    storage_class: Option<String>,
+    /// The codec to compress the object's content with, if any
+    codec: Option<String>,
+    /// The key of the pack object the file is packed into, if it is small enough to be packed
+    pack: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Commands work in the current directory, so the tests which run them take turns.
+    static CURRENT_DIR: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Runs a command as if from the command line, panicking if it fails.
+    async fn s3b(args: &[&str]) -> Outcome {
+        let matches = cli().get_matches_from(std::iter::once("s3b").chain(args.iter().copied()));
+        run(&matches)
+            .await
+            .unwrap_or_else(|err| panic!("s3b {} failed: {:#}", args.join(" "), err))
+    }
+
+    /// Creates an empty directory for a test, with a `src` directory of files to back up.
+    fn test_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("s3b-{}-{}", name, std::process::id()));
+        if root.exists() {
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+        std::fs::create_dir_all(root.join("src/dir")).unwrap();
+        std::fs::write(root.join("src/a.txt"), "alpha").unwrap();
+        std::fs::write(root.join("src/empty.txt"), "").unwrap();
+        std::fs::write(root.join("src/dir/b.txt"), "bravo ".repeat(10_000)).unwrap();
+        std::fs::write(root.join("src/dir/c.jpg"), [0xffu8, 0xd8, 0xff, 0xe0]).unwrap();
+        root
+    }
+
+    /// Lists the keys of the objects in a target which start with `prefix`, other than the database.
+    async fn list_keys(target: &str, config: &ClientConfig, prefix: &str) -> Vec<String> {
+        let s3 = S3::new(
+            &Target::parse(target).unwrap(),
+            config,
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+        let mut keys = s3
+            .list_objects(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.location.to_string())
+            .filter(|key| key.starts_with(prefix) && !key.starts_with("_s3b_db/"))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    /// Backs up, checks and drops files in a target, all in this process.
+    async fn back_up(target: &str, root: &Path) {
+        let src = root.join("src");
+        let plan = root.join("plan.bin");
+        let plan = plan.to_str().unwrap();
+        let current_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&src).unwrap();
+
+        assert_eq!(
+            s3b(&[
+                "plan",
+                "--target",
+                target,
+                "--plan-out",
+                plan,
+                "--compress",
+                "auto"
+            ])
+            .await,
+            Outcome::Done
+        );
+        assert_eq!(s3b(&["push", "--plan", plan]).await, Outcome::Done);
+        assert!(!Path::new(plan).exists());
+        assert_eq!(s3b(&["status", "--target", target]).await, Outcome::Done);
+        assert_eq!(
+            s3b(&["verify", "--target", target, "--deep"]).await,
+            Outcome::Done
+        );
+        assert_eq!(
+            s3b(&[
+                "--read-only",
+                "find",
+                "--target",
+                target,
+                "--where",
+                "key LIKE 'dir/%'"
+            ])
+            .await,
+            Outcome::Done
+        );
+        assert_eq!(
+            s3b(&["plan", "--target", target, "--plan-out", plan]).await,
+            Outcome::NothingToDo
+        );
+
+        assert_eq!(
+            s3b(&["drop", "--target", target, "--path", "dir/"]).await,
+            Outcome::Done
+        );
+        assert_eq!(
+            s3b(&["drop", "--target", target, "--path", "dir/"]).await,
+            Outcome::NothingToDo
+        );
+        assert_eq!(
+            s3b(&["drop", "--target", target, "--path", "empty.txt"]).await,
+            Outcome::Done
+        );
+        // dropping a key which was never backed up leaves the database alone
+        let s3 = S3::new(
+            &Target::parse(target).unwrap(),
+            &ClientConfig::default(),
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+        let db_etag = s3.e_tag("_s3b_db/entries.jsonl").await.unwrap();
+        assert_eq!(
+            s3b(&["drop", "--target", target, "--path", "empty.txt"]).await,
+            Outcome::NothingToDo
+        );
+        assert_eq!(s3.e_tag("_s3b_db/entries.jsonl").await.unwrap(), db_etag);
+        assert_eq!(
+            s3b(&["status", "--target", target]).await,
+            Outcome::Differences
+        );
+        assert_eq!(
+            s3b(&["verify", "--target", target, "--deep"]).await,
+            Outcome::Done
+        );
+
+        // the dropped files are planned again as new files
+        std::fs::write(src.join("empty.txt"), "no longer empty").unwrap();
+        assert_eq!(
+            s3b(&["plan", "--target", target, "--plan-out", plan]).await,
+            Outcome::Done
+        );
+        assert_eq!(s3b(&["push", "--plan", plan]).await, Outcome::Done);
+        assert_eq!(s3b(&["status", "--target", target]).await, Outcome::Done);
+        assert_eq!(
+            s3b(&["verify", "--target", target, "--deep"]).await,
+            Outcome::Done
+        );
+        assert_eq!(
+            s3b(&["drop", "--target", target, "--path", "dir/b.txt"]).await,
+            Outcome::Done
+        );
+
+        let keys = list_keys(target, &ClientConfig::default(), "").await;
+        assert_eq!(keys, vec!["a.txt", "dir/c.jpg", "empty.txt"]);
+        // the database is only ever downloaded while a command runs
+        assert!(!src.join("_s3b_db").exists());
+        std::env::set_current_dir(current_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn back_up_to_memory() {
+        let _lock = CURRENT_DIR.lock().await;
+        let root = test_dir("memory");
+        back_up("memory://", &root).await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn back_up_to_directory() {
+        let _lock = CURRENT_DIR.lock().await;
+        let root = test_dir("directory");
+        let dest = root.join("dest");
+        std::fs::create_dir(&dest).unwrap();
+        back_up(&format!("file://{}", dest.display()), &root).await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Writes a plan which pushes the current content of `paths` into a new pack, as planning
+    /// changed files would once they were selected at the prompt.
+    async fn plan_update(
+        target: &str,
+        config: &ClientConfig,
+        src: &Path,
+        paths: &[&str],
+        plan: &str,
+    ) {
+        let target = Target::parse(target).unwrap();
+        let s3 = S3::new(&target, config, RetryPolicy::default())
+            .await
+            .unwrap();
+        let base_path = src.canonicalize().unwrap();
+        let pack_key = format!(
+            "{}update-{}",
+            pack::PACK_PREFIX,
+            paths.join("-").replace('/', "-")
+        );
+        let entries = paths
+            .iter()
+            .map(|path| PlanEntry {
+                change: Change::Modified,
+                codec: Some("zstd".into()),
+                pack: Some(pack_key.clone()),
+                ..commands::plan::scan_file(
+                    base_path.join(path),
+                    base_path.to_str().unwrap(),
+                    "",
+                    None,
+                )
+                .unwrap()
+            })
+            .collect();
+        let db_etag = s3.e_tag("_s3b_db/entries.jsonl").await.unwrap();
+        Plan::new(
+            &target,
+            base_path,
+            entries,
+            db_etag,
+            None,
+            PlanEncoding::Binary,
+        )
+        .write(Path::new(plan))
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn entries_uploaded_before_a_failure_are_recorded() {
+        let _lock = CURRENT_DIR.lock().await;
+        let root = test_dir("failed-push");
+        let src = root.join("src");
+        let dest = root.join("dest");
+        std::fs::create_dir(&dest).unwrap();
+        let target = format!("file://{}", dest.display());
+        let target = target.as_str();
+        let config = ClientConfig::default();
+        let plan = root.join("plan.bin");
+        let plan = plan.to_str().unwrap();
+        let current_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&src).unwrap();
+
+        let plan_args = ["plan", "--target", target, "--plan-out", plan];
+        assert_eq!(s3b(&plan_args).await, Outcome::Done);
+        assert_eq!(s3b(&["push", "--plan", plan]).await, Outcome::Done);
+
+        // packing a.txt deletes the object which held it, which fails once it is a directory
+        std::fs::remove_file(dest.join("a.txt")).unwrap();
+        std::fs::create_dir_all(dest.join("a.txt/in-the-way")).unwrap();
+        std::fs::write(src.join("new.txt"), "new").unwrap();
+        plan_update(target, &config, &src, &["new.txt", "a.txt"], plan).await;
+        let matches = cli().get_matches_from(["s3b", "push", "--plan", plan]);
+        assert!(run(&matches).await.is_err());
+
+        // the database was still pushed with new.txt, and the plan keeps only a.txt
+        assert!(!src.join("_s3b_db").exists());
+        let remaining = Plan::read(Path::new(plan)).unwrap().entries;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].key, "a.txt");
+        let s3 = S3::new(
+            &Target::parse(target).unwrap(),
+            &config,
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+        s3.get("_s3b_db/").await.unwrap();
+        let entries = Sql::new().await.unwrap().get_entries().await.unwrap();
+        std::fs::remove_dir_all("_s3b_db").unwrap();
+        let new = entries.iter().find(|e| e.key == "new.txt").unwrap();
+        assert!(new.pack_key.is_some());
+        let a = entries.iter().find(|e| e.key == "a.txt").unwrap();
+        assert!(a.pack_key.is_none());
+
+        std::env::set_current_dir(current_dir).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn back_up_packed_files() {
+        let _lock = CURRENT_DIR.lock().await;
+        let root = test_dir("packed");
+        let src = root.join("src");
+        std::fs::remove_dir_all(&src).unwrap();
+        let long_name = format!("small/{}.txt", "a long file name ".repeat(8));
+        std::fs::create_dir_all(src.join("small")).unwrap();
+        for name in ["small/a.txt", "small/b.txt", "small/c.txt", &long_name] {
+            std::fs::write(
+                src.join(name),
+                format!("the content of {}", name).repeat(100),
+            )
+            .unwrap();
+        }
+        std::fs::write(src.join("small/empty.txt"), "").unwrap();
+        std::fs::write(src.join("big.txt"), "big ".repeat(100_000)).unwrap();
+        let dest = root.join("dest");
+        std::fs::create_dir(&dest).unwrap();
+        let target = format!("file://{}", dest.display());
+        let target = target.as_str();
+        let key_file = root.join("key");
+        std::fs::write(&key_file, [9u8; 32]).unwrap();
+        let key_file = key_file.to_str().unwrap();
+        let config = ClientConfig {
+            key: Some(crypto::KeySource::File(key_file.into())),
+            ..ClientConfig::default()
+        };
+        let plan = root.join("plan.bin");
+        let plan = plan.to_str().unwrap();
+        let current_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&src).unwrap();
+
+        // packed members are compressed and encrypted on their own, and read back by range
+        let args = [
+            "--key-file",
+            key_file,
+            "plan",
+            "--target",
+            target,
+            "--plan-out",
+            plan,
+        ];
+        let outcome = s3b(&[&args[..], &["--pack", "64K", "--compress", "zstd"]].concat()).await;
+        assert_eq!(outcome, Outcome::Done);
+        assert_eq!(
+            s3b(&["--key-file", key_file, "push", "--plan", plan]).await,
+            Outcome::Done
+        );
+        let packs = list_keys(target, &config, pack::PACK_PREFIX).await;
+        assert_eq!(packs.len(), 1);
+        let verify = [
+            "--key-file",
+            key_file,
+            "verify",
+            "--target",
+            target,
+            "--deep",
+        ];
+        assert_eq!(s3b(&verify).await, Outcome::Done);
+        let restored = root.join("restored");
+        std::fs::create_dir(&restored).unwrap();
+        let restored_str = restored.to_str().unwrap();
+        let restore = [
+            "--key-file",
+            key_file,
+            "restore",
+            "--target",
+            target,
+            "--to",
+            restored_str,
+        ];
+        assert_eq!(s3b(&restore).await, Outcome::Done);
+        for name in ["small/a.txt", "small/empty.txt", &long_name, "big.txt"] {
+            let restored = std::fs::read(restored.join(name)).unwrap();
+            assert_eq!(restored, std::fs::read(src.join(name)).unwrap(), "{}", name);
+        }
+        // restored files are recognised by their keyed hashes, and a changed one is not overwritten
+        assert_eq!(s3b(&restore).await, Outcome::NothingToDo);
+        std::fs::write(restored.join("big.txt"), "changed").unwrap();
+        let matches = cli().get_matches_from(std::iter::once("s3b").chain(restore));
+        let err = run(&matches).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Conflict(_))
+        ));
+
+        // the pack is kept while any of its members is current
+        for name in ["small/a.txt", "small/b.txt"] {
+            std::fs::write(src.join(name), "changed").unwrap();
+        }
+        plan_update(target, &config, &src, &["small/a.txt", "small/b.txt"], plan).await;
+        assert_eq!(
+            s3b(&["--key-file", key_file, "push", "--plan", plan]).await,
+            Outcome::Done
+        );
+        let updated = list_keys(target, &config, pack::PACK_PREFIX).await;
+        assert_eq!(updated.len(), 2);
+        assert!(updated.contains(&packs[0]));
+        assert_eq!(s3b(&verify).await, Outcome::Done);
+        let drop = ["--key-file", key_file, "drop", "--target", target, "--path"];
+        assert_eq!(
+            s3b(&[&drop[..], &["small/c.txt"]].concat()).await,
+            Outcome::Done
+        );
+        assert_eq!(list_keys(target, &config, pack::PACK_PREFIX).await, updated);
+        assert_eq!(
+            s3b(&[&drop[..], &[long_name.as_str()]].concat()).await,
+            Outcome::Done
+        );
+        assert_eq!(list_keys(target, &config, pack::PACK_PREFIX).await, updated);
+
+        // and deleted once the last is dropped
+        assert_eq!(
+            s3b(&[&drop[..], &["small/empty.txt"]].concat()).await,
+            Outcome::Done
+        );
+        let remaining = list_keys(target, &config, pack::PACK_PREFIX).await;
+        assert_eq!(remaining.len(), 1);
+        assert!(!remaining.contains(&packs[0]));
+        assert_eq!(s3b(&verify).await, Outcome::Done);
+
+        // or once the last is replaced
+        plan_update(
+            target,
+            &config,
+            &src,
+            &["small/a.txt", "small/b.txt", "big.txt"],
+            plan,
+        )
+        .await;
+        assert_eq!(
+            s3b(&["--key-file", key_file, "push", "--plan", plan]).await,
+            Outcome::Done
+        );
+        let replaced = list_keys(target, &config, pack::PACK_PREFIX).await;
+        assert_eq!(replaced.len(), 1);
+        assert!(!replaced.contains(&remaining[0]));
+        assert_eq!(s3b(&verify).await, Outcome::Done);
+
+        std::env::set_current_dir(current_dir).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gc_only_aborts_uploads_under_the_prefix() {
+        let _lock = CURRENT_DIR.lock().await;
+        let root = test_dir("gc");
+        let dest = root.join("dest");
+        let target = format!("file://{}", dest.display());
+        let target = target.as_str();
+        // uploads staged by this backup, by another host, and one too recent to abort
+        let staged = [
+            "laptop/a.txt#0000000001",
+            "desktop/a.txt#0000000002",
+            "laptop/b.txt#0000000003",
+        ];
+        for upload in staged {
+            let path = dest.join(upload);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "part").unwrap();
+        }
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(48 * 3600);
+        for upload in &staged[..2] {
+            std::fs::File::options()
+                .write(true)
+                .open(dest.join(upload))
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        let current_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&root).unwrap();
+
+        // without a prefix, the uploads of every client would be aborted
+        let matches = cli().get_matches_from(["s3b", "gc", "multipart", "--target", target]);
+        let err = run(&matches).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Config(_))
+        ));
+
+        let gc = ["gc", "multipart", "--target", target, "--prefix", "laptop"];
+        assert_eq!(s3b(&gc).await, Outcome::Done);
+        assert!(!dest.join(staged[0]).exists());
+        assert!(dest.join(staged[1]).exists() && dest.join(staged[2]).exists());
+        assert_eq!(s3b(&gc).await, Outcome::NothingToDo);
+
+        assert_eq!(
+            s3b(&["gc", "multipart", "--target", target, "--all"]).await,
+            Outcome::Done
+        );
+        assert!(!dest.join(staged[1]).exists() && dest.join(staged[2]).exists());
+
+        std::env::set_current_dir(current_dir).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn diff_compares_two_directories() {
+        let a = test_dir("diff-a").join("src");
+        let b = test_dir("diff-b").join("src");
+        let (a_dir, b_dir) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let diff = ["diff", "--dir", a_dir, "--dir", b_dir];
+        assert_eq!(s3b(&diff).await, Outcome::Done);
+
+        std::fs::write(b.join("a.txt"), "changed").unwrap();
+        std::fs::rename(b.join("dir/b.txt"), b.join("moved.txt")).unwrap();
+        std::fs::write(b.join("new.txt"), "new").unwrap();
+        assert_eq!(s3b(&diff).await, Outcome::Differences);
+        assert_eq!(
+            s3b(&[&diff[..], &["--prefix", "laptop", "--json"]].concat()).await,
+            Outcome::Differences
+        );
+
+        std::fs::remove_dir_all(a.parent().unwrap()).unwrap();
+        std::fs::remove_dir_all(b.parent().unwrap()).unwrap();
+    }
 }
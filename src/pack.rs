@@ -0,0 +1,129 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{sql::quote, Sql, S3};
+
+/// Pack objects are stored under this prefix, which s3b keeps out of the hidden names of an
+/// encrypted target.
+pub const PACK_PREFIX: &str = "_s3b_packs/";
+/// A pack is closed once adding the next file would make it larger than this.
+pub const PACK_SIZE: u64 = 32 * 1024 * 1024;
+/// The largest file size `--pack` accepts, so that a pack holds at least a few files.
+pub const MAX_PACK_THRESHOLD: u64 = 8 * 1024 * 1024;
+const TAR_BLOCK: u64 = 512;
+
+/// Where the content of a packed entry is stored: `length` bytes at `offset` in the pack object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackRange {
+    pub pack_key: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Builds a pack object in memory. Packs are tar archives, so an unencrypted pack can be
+/// unpacked without s3b; each member holds a file's content as it would be stored on its own,
+/// i.e. compressed and encrypted independently of the other members.
+pub struct PackBuilder {
+    builder: tar::Builder<Vec<u8>>,
+}
+
+impl PackBuilder {
+    pub fn new() -> Self {
+        PackBuilder {
+            builder: tar::Builder::new(Vec::new()),
+        }
+    }
+
+    /// Appends a member named `name`, returning the offset and length of its content.
+    pub fn append(
+        &mut self,
+        name: &str,
+        modified: u64,
+        content: &[u8],
+    ) -> anyhow::Result<(u64, u64)> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(modified);
+        header.set_entry_type(tar::EntryType::Regular);
+        self.builder.append_data(&mut header, name, content)?;
+        // the content is followed by padding to a whole block
+        let end = self.builder.get_ref().len() as u64;
+        let length = content.len() as u64;
+        Ok((end - length.next_multiple_of(TAR_BLOCK), length))
+    }
+
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.builder.into_inner()?)
+    }
+}
+
+/// Deletes those of `pack_keys` which no entry refers to any more, returning how many were
+/// deleted. A pack is only deleted once every file in it has been dropped or replaced.
+pub async fn delete_unreferenced<'a>(
+    s3: &S3,
+    sql: &mut Sql,
+    pack_keys: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<usize> {
+    let mut num_deleted = 0;
+    for pack_key in pack_keys.into_iter().collect::<BTreeSet<_>>() {
+        let referenced = sql
+            .get_entries_where(&format!("pack_key={}", quote(pack_key)))
+            .await?;
+        if referenced.is_empty() {
+            s3.delete(pack_key).await?;
+            num_deleted += 1;
+        }
+    }
+    Ok(num_deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_are_found_at_their_offsets() {
+        let long_name = format!("{}/file.txt", "long directory name".repeat(8));
+        let members: Vec<(&str, Vec<u8>)> = vec![
+            ("a.txt", b"alpha".to_vec()),
+            ("empty.txt", Vec::new()),
+            // longer than the 100 bytes a tar header holds, so it takes a GNU long name entry
+            (&long_name, vec![7u8; 1000]),
+            ("block.bin", vec![1u8; TAR_BLOCK as usize]),
+            ("last.txt", b"omega".to_vec()),
+        ];
+        let mut builder = PackBuilder::new();
+        let ranges = members
+            .iter()
+            .map(|(name, content)| builder.append(name, 1_700_000_000, content).unwrap())
+            .collect::<Vec<_>>();
+        let pack = builder.finish().unwrap();
+
+        for ((name, content), (offset, length)) in members.iter().zip(&ranges) {
+            assert_eq!(*length, content.len() as u64, "{}", name);
+            let member = &pack[*offset as usize..(offset + length) as usize];
+            assert_eq!(member, content.as_slice(), "{}", name);
+        }
+
+        // the pack is an ordinary tar archive
+        let mut archive = tar::Archive::new(pack.as_slice());
+        let unpacked = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_str().unwrap().to_string();
+                let mut content = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                (name, content)
+            })
+            .collect::<Vec<_>>();
+        let members = members
+            .into_iter()
+            .map(|(name, content)| (name.to_string(), content))
+            .collect::<Vec<_>>();
+        assert_eq!(unpacked, members);
+    }
+}
@@ -0,0 +1,266 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use clap::{arg, value_parser, ArgMatches, Command};
+use rand::Rng;
+
+use crate::Error;
+
+/// Error kinds which may be passed to `--retry-on`; see [`Error::kind`].
+const RETRYABLE_KINDS: [&str; 4] = ["network", "auth", "not-found", "conflict"];
+
+/// Controls how S3 operations are retried after a failure. Delays grow exponentially from
+/// `base_delay` up to `max_delay`, and each delay is drawn at random from zero up to that bound.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub retry_on: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(20),
+            retry_on: vec!["network".into()],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Adds the global retry arguments to the root command.
+    pub fn args(command: Command) -> Command {
+        command
+            .arg(
+                arg!(--"max-attempts" <N> "Maximum number of attempts for each S3 operation")
+                    .value_parser(value_parser!(u32).range(1..))
+                    .global(true),
+            )
+            .arg(
+                arg!(--"retry-backoff" <MS> "Initial delay in milliseconds before retrying a failed S3 operation")
+                    .value_parser(value_parser!(u64))
+                    .global(true),
+            )
+            .arg(
+                arg!(--"retry-max-backoff" <MS> "Maximum delay in milliseconds between retries")
+                    .value_parser(value_parser!(u64))
+                    .global(true),
+            )
+            .arg(
+                arg!(--"retry-on" <KINDS> "Space-separated list of error kinds to retry")
+                    .value_parser(RETRYABLE_KINDS)
+                    .value_delimiter(' ')
+                    .num_args(1..)
+                    .global(true),
+            )
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: matches
+                .get_one::<u32>("max-attempts")
+                .copied()
+                .unwrap_or(default.max_attempts),
+            base_delay: matches
+                .get_one::<u64>("retry-backoff")
+                .map(|ms| Duration::from_millis(*ms))
+                .unwrap_or(default.base_delay),
+            max_delay: matches
+                .get_one::<u64>("retry-max-backoff")
+                .map(|ms| Duration::from_millis(*ms))
+                .unwrap_or(default.max_delay),
+            retry_on: match matches.get_many::<String>("retry-on") {
+                Some(kinds) => kinds.cloned().collect(),
+                None => default.retry_on,
+            },
+        }
+    }
+
+    /// Runs `op` until it succeeds, fails with an error which is not retryable, or has been
+    /// attempted `max_attempts` times. Each retry is counted in `retries`.
+    pub async fn retry<T, F, Fut>(&self, retries: &AtomicU64, mut op: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(res) => return Ok(res),
+                Err(err) if attempt < self.max_attempts && self.is_retryable(&err) => {
+                    tokio::time::sleep(self.delay(attempt)).await;
+                    retries.fetch_add(1, Ordering::Relaxed);
+                    attempt += 1;
+                }
+                Err(err) if attempt > 1 => {
+                    return Err(err.context(format!("gave up after {} attempts", attempt)))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn is_retryable(&self, err: &anyhow::Error) -> bool {
+        match err.downcast_ref::<Error>() {
+            Some(err) => self.retry_on.iter().any(|kind| kind == err.kind()),
+            None => false,
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let bound = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    fn policy(max_attempts: u32, retry_on: &[&str]) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            retry_on: retry_on.iter().map(|kind| kind.to_string()).collect(),
+        }
+    }
+
+    /// Runs `policy` on an operation which fails with `err` the first `failures` times, returning
+    /// the result, the number of attempts and the number of retries counted.
+    async fn run(
+        policy: &RetryPolicy,
+        failures: u32,
+        err: fn() -> anyhow::Error,
+    ) -> (anyhow::Result<()>, u32, u64) {
+        let attempts = AtomicU32::new(0);
+        let retries = AtomicU64::new(0);
+        let result = policy
+            .retry(&retries, || async {
+                match attempts.fetch_add(1, Ordering::Relaxed) < failures {
+                    true => Err(err()),
+                    false => Ok(()),
+                }
+            })
+            .await;
+        (result, attempts.into_inner(), retries.into_inner())
+    }
+
+    fn network() -> anyhow::Error {
+        Error::Network("connection reset".into()).into()
+    }
+
+    fn auth() -> anyhow::Error {
+        Error::Auth("access denied".into()).into()
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let (result, attempts, retries) = run(&policy(5, &["network"]), 2, network).await;
+        assert!(result.is_ok());
+        assert_eq!((attempts, retries), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (result, attempts, retries) = run(&policy(3, &["network"]), 10, network).await;
+        let err = result.unwrap_err();
+        assert_eq!((attempts, retries), (3, 2));
+        assert_eq!(err.to_string(), "gave up after 3 attempts");
+        // the error keeps its kind, and so its exit code
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Network(_))
+        ));
+
+        let (result, attempts, retries) = run(&policy(1, &["network"]), 10, network).await;
+        assert!(result.is_err());
+        assert_eq!((attempts, retries), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn only_retries_the_kinds_given() {
+        let (result, attempts, _) = run(&policy(5, &["network"]), 2, auth).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::Auth(_))
+        ));
+        assert_eq!(attempts, 1);
+
+        let (result, attempts, _) = run(&policy(5, &["network", "auth"]), 2, auth).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+
+        // errors which s3b doesn't classify are never retried
+        let (result, attempts, _) = run(&policy(5, &RETRYABLE_KINDS), 2, || {
+            anyhow::anyhow!("unclassified")
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            ..RetryPolicy::default()
+        };
+        for attempt in 1..=64 {
+            let bound = Duration::from_millis(100 << (attempt - 1).min(4)).min(policy.max_delay);
+            for _ in 0..100 {
+                assert!(policy.delay(attempt) <= bound, "attempt {}", attempt);
+            }
+        }
+        // the delays are spread out rather than all at the bound
+        let delays = (0..100).map(|_| policy.delay(4)).collect::<Vec<_>>();
+        assert!(delays
+            .iter()
+            .any(|delay| *delay < Duration::from_millis(400)));
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let command = RetryPolicy::args(Command::new("s3b"));
+        let policy = RetryPolicy::from_matches(&command.clone().get_matches_from(["s3b"]));
+        assert_eq!(policy.max_attempts, 5);
+        assert_eq!(policy.retry_on, vec!["network"]);
+
+        let matches = command.clone().get_matches_from([
+            "s3b",
+            "--max-attempts",
+            "3",
+            "--retry-backoff",
+            "50",
+            "--retry-max-backoff",
+            "500",
+            "--retry-on",
+            "network auth",
+        ]);
+        let policy = RetryPolicy::from_matches(&matches);
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.base_delay, Duration::from_millis(50));
+        assert_eq!(policy.max_delay, Duration::from_millis(500));
+        assert_eq!(policy.retry_on, vec!["network", "auth"]);
+
+        // integrity errors are never worth retrying
+        assert!(command
+            .clone()
+            .try_get_matches_from(["s3b", "--retry-on", "integrity"])
+            .is_err());
+        assert!(command
+            .try_get_matches_from(["s3b", "--max-attempts", "0"])
+            .is_err());
+    }
+}
@@ -1,77 +1,643 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::{Read, Write},
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
-use aws_config::meta::region::RegionProviderChain;
-use aws_credential_types::provider::ProvideCredentials;
-use futures::StreamExt;
+use async_trait::async_trait;
+use aws_config::{
+    default_provider::region::DefaultRegionChain, meta::region::RegionProviderChain, Region,
+};
+use aws_credential_types::{
+    provider::{ProvideCredentials, SharedCredentialsProvider},
+    Credentials,
+};
+use aws_sdk_s3::{
+    error::{DisplayErrorContext, ProvideErrorMetadata},
+    primitives::ByteStream,
+    types::{
+        ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, GlacierJobParameters,
+        RestoreRequest, ServerSideEncryption, StorageClass, Tier,
+    },
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use clap::{arg, value_parser, ArgMatches, Command};
+use futures::{StreamExt, TryStreamExt};
+use md5::Md5;
 use object_store::{
-    aws::{AmazonS3, AmazonS3Builder},
+    aws::{AmazonS3, AmazonS3Builder, AwsCredential, Checksum},
+    azure::MicrosoftAzureBuilder,
+    gcp::GoogleCloudStorageBuilder,
+    local::LocalFileSystem,
+    memory::InMemory,
+    multipart::{MultipartStore, PartId},
     path::Path as ObjectPath,
-    Error, ObjectStore, PutPayload, WriteMultipart,
+    CredentialProvider, GetOptions, GetRange, ObjectMeta, ObjectStore, PutPayload, RetryConfig,
 };
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
+use crate::{
+    compress::{Codec, Decoder},
+    config::{Config, TargetConfig},
+    crypto::{self, read_key, KeySource, Keys, Sealer, BLOCK_SIZE, KEY_OBJECT},
+    local::LocalMultipart,
+    pack::{PackBuilder, PackRange},
+    parse_size,
+    target::Target,
+    throttle::{PacedBody, RateLimiter, CHUNK_SIZE},
+    uploads::{MultipartState, UploadLog, UploadedPart},
+    Error, RetryPolicy,
+};
+
+/// S3 allows at most 10,000 parts in a multipart upload.
+const MAX_PARTS: u64 = 10_000;
+/// S3 requires every part but the last to be at least 5 MiB.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// S3 allows each part to be at most 5 GiB.
+const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// S3 allows an object uploaded with a single request to be at most 5 GB.
+const MAX_PUT_SIZE: u64 = 5_000_000_000;
+/// How long before temporary credentials expire to refresh them.
+const CREDENTIAL_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// The store behind `memory://`, shared by every client in the process, so that commands run
+/// one after another in a test see each other's objects.
+fn memory_store() -> Arc<InMemory> {
+    static MEMORY: OnceLock<Arc<InMemory>> = OnceLock::new();
+    MEMORY.get_or_init(|| Arc::new(InMemory::new())).clone()
+}
+
 pub struct S3 {
-    client: AmazonS3,
+    client: Arc<dyn ObjectStore>,
+    /// Multipart uploads go through `client` for every target but a local directory
+    parts: Arc<dyn MultipartStore>,
+    /// Used for the few operations which object_store does not support; only set for S3 targets
+    sdk: Option<aws_sdk_s3::Client>,
+    /// Only set for local directory targets, to list their multipart uploads
+    local: Option<Arc<LocalMultipart>>,
+    target: Target,
+    read_only: bool,
+    /// Only set for S3 targets, to encrypt objects uploaded through the SDK as object_store does
+    encryption: Option<Encryption>,
+    /// Set for an encrypted target, to encrypt objects before they are uploaded
+    keys: Option<Keys>,
+    retry: RetryPolicy,
+    retries: AtomicU64,
+    /// Shared with the request bodies it paces
+    limiter: Arc<RateLimiter>,
+    multipart: MultipartConfig,
+    uploads: Mutex<UploadLog>,
+    /// Whether `uploads` is written to disk, so that interrupted uploads can be resumed
+    persist_uploads: bool,
 }
 
-impl S3 {
-    pub async fn new(bucket_name: &str, endpoint: Option<&str>) -> anyhow::Result<Self> {
-        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-        let config = aws_config::from_env().region(region_provider).load().await;
-        let creds = config
-            .credentials_provider()
-            .unwrap()
-            .provide_credentials()
-            .await
-            .unwrap();
+/// The result of uploading a file, as confirmed by the remote.
+pub struct Uploaded {
+    pub size: u64,
+    /// The BLAKE3 hash of the bytes which were uploaded, computed as they were read
+    pub hash: String,
+    pub e_tag: Option<String>,
+    /// The SHA-256 checksum of the object which the remote verified, base64 encoded, as S3
+    /// returned it; for multipart uploads it is the checksum of the part checksums, suffixed
+    /// with `-<parts>`. None for targets which return no checksum.
+    pub checksum: Option<String>,
+    /// How the content was compressed; None if it was not, including when compressing it
+    /// would not have made it smaller
+    pub codec: Option<Codec>,
+    /// Where the content was stored, if it was packed with other files
+    pub pack: Option<PackRange>,
+}
+
+/// A multipart upload which was started but has not been completed or aborted.
+pub struct PendingUpload {
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: Option<DateTime<Utc>>,
+}
+
+/// Whether an object can be downloaded, or must first be restored from an archive storage class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveState {
+    /// The object is not archived, or a restored copy of it is available
+    Available,
+    /// The object is archived with the given storage class, and no restore is in progress
+    Archived(String),
+    /// A restore of the object has been requested and is in progress
+    Restoring,
+}
+
+/// Controls how large files are split into parts. Files no larger than `part_size` are uploaded
+/// with a single request; at most `concurrency` parts are read into memory and sent at once.
+#[derive(Debug, Clone)]
+pub struct MultipartConfig {
+    pub part_size: u64,
+    pub concurrency: usize,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            part_size: 50_000_000,
+            concurrency: 4,
+        }
+    }
+}
 
-        let mut builder = AmazonS3Builder::new()
-            .with_access_key_id(creds.access_key_id())
-            .with_secret_access_key(creds.secret_access_key())
-            .with_region(
-                config
-                    .region()
-                    .expect("expected aws region to be set")
-                    .as_ref(),
+impl MultipartConfig {
+    /// Adds the multipart arguments to an upload command.
+    pub fn args(command: Command) -> Command {
+        command
+            .arg(
+                arg!(--"part-size" <SIZE> "Size of each part of a multipart upload, with an optional K, M or G suffix; from 5M to 5G")
+                    .value_parser(|size: &str| match parse_size(size) {
+                        Ok(size) if size < MIN_PART_SIZE => Err(anyhow!("part size must be at least 5M")),
+                        Ok(size) if size > MAX_PART_SIZE => Err(anyhow!("part size must be at most 5G")),
+                        res => res,
+                    }),
+            )
+            .arg(
+                arg!(--"part-concurrency" <N> "Maximum number of parts of a multipart upload to send at once")
+                    .value_parser(value_parser!(u32).range(1..)),
             )
-            .with_bucket_name(bucket_name);
+    }
 
-        if let Some(ep) = endpoint {
-            builder = builder.with_endpoint(ep);
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let default = Self::default();
+        Self {
+            part_size: matches
+                .get_one::<u64>("part-size")
+                .copied()
+                .unwrap_or(default.part_size),
+            concurrency: matches
+                .get_one::<u32>("part-concurrency")
+                .map(|n| *n as usize)
+                .unwrap_or(default.concurrency),
         }
+    }
+
+    /// The part size to use for a file of `len` bytes, grown if needed to stay within `MAX_PARTS`
+    /// but never beyond `MAX_PART_SIZE`.
+    fn part_size_for(&self, len: u64) -> u64 {
+        self.part_size
+            .max(len.div_ceil(MAX_PARTS))
+            .clamp(MIN_PART_SIZE, MAX_PART_SIZE)
+    }
 
-        match builder.build() {
-            Ok(client) => Ok(Self { client }),
-            Err(err) => Err(err.into()),
+    /// The part size to upload a file of `len` bytes with. Parts of an encrypted object hold
+    /// whole blocks and grow when they are sealed, so they may be smaller than `part_size_for`,
+    /// which can leave a large file with more parts than S3 allows.
+    fn upload_part_size(&self, len: u64, encrypted: bool) -> anyhow::Result<u64> {
+        let mut part_size = self.part_size_for(len);
+        if encrypted {
+            part_size = part_size.next_multiple_of(BLOCK_SIZE);
+            while crypto::sealed_len(part_size) > MAX_PART_SIZE {
+                part_size -= BLOCK_SIZE;
+            }
+        }
+        if len.div_ceil(part_size) > MAX_PARTS {
+            return Err(Error::Config(format!(
+                "a file of {} bytes needs more than {} parts of at most {} bytes, which is too large to upload",
+                len, MAX_PARTS, part_size
+            ))
+            .into());
         }
+        Ok(part_size)
     }
+}
 
-    pub async fn key_exists(&self, key: &str) -> anyhow::Result<bool> {
-        match self.client.head(&ObjectPath::from(key)).await {
-            Ok(_) => Ok(true),
-            Err(Error::NotFound { path: _, source: _ }) => Ok(false),
-            Err(Error::Generic {
-                store: _,
-                source: _,
-            }) => Err(anyhow!("Generic S3 error: does the bucket exist?")),
-            Err(err) => Err(err.into()),
+/// How the client connects to the target, shared by every command. Only `endpoint` and
+/// `allow_http` apply to Azure targets, and none but `read_only` to GCS and local targets.
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub endpoint: Option<String>,
+    /// The AWS profile to load credentials and the region from, instead of the default profile
+    pub profile: Option<String>,
+    pub region: Option<String>,
+    /// Address buckets as `<endpoint>/<bucket>` rather than `<bucket>.<endpoint>`; always
+    /// used with a custom endpoint
+    pub path_style: bool,
+    pub allow_http: bool,
+    /// Send unsigned requests, for public buckets; implies `read_only`
+    pub anonymous: bool,
+    /// Refuse to upload or delete anything
+    pub read_only: bool,
+    /// Server-side encryption of new objects, `aes256` or `kms`
+    pub sse: Option<String>,
+    /// The KMS key to encrypt new objects with; implies `sse` of `kms`
+    pub kms_key_id: Option<String>,
+    /// A file holding the 256-bit key for SSE-C, which encrypts and decrypts every object
+    pub sse_c_key: Option<PathBuf>,
+    /// The key to encrypt objects and the database with before they are uploaded
+    pub key: Option<KeySource>,
+    /// Whether a newly encrypted target keys its hashes, so that they don't reveal content
+    pub keyed_hashes: bool,
+}
+
+/// Server-side encryption, applied to every object and part uploaded, including the database.
+#[derive(Debug, Clone)]
+pub enum Encryption {
+    /// SSE-S3, with keys managed by S3
+    Aes256,
+    /// SSE-KMS, with the given key or else the account's default key for S3
+    Kms(Option<String>),
+    /// SSE-C, with a key which S3 does not keep and which must be sent to read the objects back
+    Customer([u8; 32]),
+}
+
+impl ClientConfig {
+    /// Adds the global client arguments to the root command.
+    pub fn args(command: Command) -> Command {
+        command
+            .arg(arg!(--"profile" <PROFILE> "The AWS profile to take credentials and the region from").global(true))
+            .arg(arg!(--"region" <REGION> "The region of the bucket, overriding the profile and environment").global(true))
+            .arg(arg!(--"path-style" "Use path-style requests, e.g. for bucket names containing dots").global(true))
+            .arg(arg!(--"allow-http" "Allow connecting to an http:// endpoint, e.g. a local MinIO server").global(true))
+            .arg(arg!(--"anonymous" "Access a public bucket without credentials; implies --read-only").global(true))
+            .arg(arg!(--"read-only" "Refuse to upload or delete anything").global(true))
+            .arg(
+                arg!(--"sse" <MODE> "Encrypt new objects at rest with S3-managed keys (aes256) or KMS keys (kms)")
+                    .value_parser(["aes256", "kms"])
+                    .global(true),
+            )
+            .arg(arg!(--"kms-key-id" <ID> "The KMS key to encrypt new objects with; implies --sse kms").global(true))
+            .arg(
+                arg!(--"sse-c-key" <FILE> "Encrypt objects with the 256-bit key in FILE, which is needed to read them again")
+                    .conflicts_with_all(["sse", "kms-key-id"])
+                    .global(true),
+            )
+            .arg(arg!(--"key-file" <FILE> "Encrypt objects and the database before uploading them, with the 256-bit key in FILE").global(true))
+            .arg(
+                arg!(--"passphrase" "Encrypt objects and the database with a key derived from a passphrase, read from S3B_PASSPHRASE or prompted for")
+                    .conflicts_with("key-file")
+                    .global(true),
+            )
+            .arg(arg!(--"keyed-hashes" "When first encrypting a target, record keyed hashes which don't reveal identical content").global(true))
+    }
+
+    /// Reads the client arguments, along with `--endpoint` if the command has it, falling back
+    /// to the options of the command's target in `s3b.toml`.
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        Ok(Self::for_target(matches, Config::selected_target(matches)?))
+    }
+
+    /// Reads the client arguments, falling back to the options of the given configured target.
+    pub fn for_target(matches: &ArgMatches, named: Option<&TargetConfig>) -> Self {
+        let named = named.cloned().unwrap_or_default();
+        let anonymous = matches.get_flag("anonymous");
+        // encryption options given on the command line replace those of the target as a whole
+        let sse = matches.get_one::<String>("sse").cloned();
+        let kms_key_id = matches.get_one::<String>("kms-key-id").cloned();
+        let sse_c_key = matches.get_one::<String>("sse-c-key").map(PathBuf::from);
+        let (sse, kms_key_id, sse_c_key) = match (sse, kms_key_id, sse_c_key) {
+            (None, None, None) => (named.sse, named.kms_key_id, named.sse_c_key),
+            given => given,
+        };
+        let key = match (
+            matches.get_one::<String>("key-file"),
+            matches.get_flag("passphrase"),
+        ) {
+            (Some(path), _) => Some(KeySource::File(PathBuf::from(path))),
+            (None, true) => Some(KeySource::Passphrase),
+            (None, false) => match (named.key_file, named.passphrase) {
+                (Some(path), _) => Some(KeySource::File(path)),
+                (None, true) => Some(KeySource::Passphrase),
+                (None, false) => None,
+            },
+        };
+        Self {
+            endpoint: matches
+                .try_get_one::<String>("endpoint")
+                .ok()
+                .flatten()
+                .cloned()
+                .or(named.endpoint),
+            profile: matches
+                .get_one::<String>("profile")
+                .cloned()
+                .or(named.profile),
+            region: matches
+                .get_one::<String>("region")
+                .cloned()
+                .or(named.region),
+            path_style: matches.get_flag("path-style") || named.path_style,
+            allow_http: matches.get_flag("allow-http") || named.allow_http,
+            anonymous,
+            read_only: anonymous || matches.get_flag("read-only"),
+            sse,
+            kms_key_id,
+            sse_c_key,
+            key,
+            keyed_hashes: matches.get_flag("keyed-hashes") || named.keyed_hashes,
+        }
+    }
+
+    /// Resolves the encryption options, reading the SSE-C key if one is given.
+    pub fn encryption(&self) -> anyhow::Result<Option<Encryption>> {
+        match (self.sse.as_deref(), &self.kms_key_id, &self.sse_c_key) {
+            (None, None, None) => Ok(None),
+            (None, None, Some(path)) => Ok(Some(Encryption::Customer(read_key(path, "SSE-C")?))),
+            (Some("aes256"), None, None) => Ok(Some(Encryption::Aes256)),
+            (Some("kms") | None, kms_key_id, None) => Ok(Some(Encryption::Kms(kms_key_id.clone()))),
+            (Some("aes256"), Some(_), None) => {
+                Err(Error::Config("a KMS key ID can only be given with --sse kms".into()).into())
+            }
+            (_, _, Some(_)) => Err(Error::Config(
+                "an SSE-C key can't be combined with --sse or --kms-key-id".into(),
+            )
+            .into()),
+            (Some(sse), _, None) => Err(Error::Config(format!(
+                "unknown server-side encryption '{}'; expected aes256 or kms",
+                sse
+            ))
+            .into()),
+        }
+    }
+}
+
+impl Encryption {
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Encryption::Aes256 => Some(ServerSideEncryption::Aes256),
+            Encryption::Kms(_) => Some(ServerSideEncryption::AwsKms),
+            Encryption::Customer(_) => None,
+        }
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Encryption::Kms(kms_key_id) => kms_key_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn customer_algorithm(&self) -> Option<String> {
+        self.customer_key().map(|_| "AES256".to_string())
+    }
+
+    /// The SSE-C key, base64 encoded as S3 takes it.
+    fn customer_key(&self) -> Option<String> {
+        match self {
+            Encryption::Customer(key) => Some(BASE64_STANDARD.encode(key)),
+            _ => None,
+        }
+    }
+
+    /// The MD5 digest of the SSE-C key, which S3 uses to check that the key arrived intact.
+    fn customer_key_md5(&self) -> Option<String> {
+        match self {
+            Encryption::Customer(key) => Some(BASE64_STANDARD.encode(Md5::digest(key))),
+            _ => None,
+        }
+    }
+}
+
+impl S3 {
+    pub async fn new(
+        target: &Target,
+        config: &ClientConfig,
+        retry: RetryPolicy,
+    ) -> anyhow::Result<Self> {
+        if let Some(ep) = &config.endpoint {
+            if ep.starts_with("http://") && !config.allow_http {
+                return Err(Error::Config(format!(
+                    "endpoint {} uses plain HTTP; pass --allow-http to connect to it",
+                    ep
+                ))
+                .into());
+            }
+        }
+        let encryption = config.encryption()?;
+        if encryption.is_some() && !matches!(target, Target::S3(_)) {
+            return Err(Error::Config(format!(
+                "server-side encryption options are only supported for S3 targets, not {}",
+                target
+            ))
+            .into());
+        }
+        let (client, parts, sdk, local): (Arc<dyn ObjectStore>, Arc<dyn MultipartStore>, _, _) =
+            match target {
+                Target::S3(bucket_name) => {
+                    let (client, sdk) = amazon_s3(bucket_name, config, encryption.as_ref()).await?;
+                    let client = Arc::new(client);
+                    (client.clone(), client, Some(sdk), None)
+                }
+                // credentials for GCS and Azure are taken from their usual environment variables
+                Target::Gcs(bucket_name) => {
+                    let client = GoogleCloudStorageBuilder::from_env()
+                        .with_bucket_name(bucket_name)
+                        .with_retry(no_retries())
+                        .build()
+                        .map_err(|err| Error::Config(err.to_string()))?;
+                    let client = Arc::new(client);
+                    (client.clone(), client, None, None)
+                }
+                Target::Azure(container) => {
+                    let mut builder = MicrosoftAzureBuilder::from_env()
+                        .with_container_name(container)
+                        .with_allow_http(config.allow_http)
+                        .with_retry(no_retries());
+                    if let Some(ep) = &config.endpoint {
+                        builder = builder.with_endpoint(ep.to_string());
+                    }
+                    let client = Arc::new(
+                        builder
+                            .build()
+                            .map_err(|err| Error::Config(err.to_string()))?,
+                    );
+                    (client.clone(), client, None, None)
+                }
+                Target::Local(root) => {
+                    if !root.is_dir() {
+                        return Err(Error::NotFound(format!(
+                            "directory {} does not exist",
+                            root.display()
+                        ))
+                        .into());
+                    }
+                    let client = LocalFileSystem::new_with_prefix(root)
+                        .map_err(|err| Error::Config(err.to_string()))?;
+                    let local = Arc::new(LocalMultipart::new(root.canonicalize()?));
+                    (Arc::new(client), local.clone(), None, Some(local))
+                }
+                Target::Memory => {
+                    let client = memory_store();
+                    (client.clone(), client, None, None)
+                }
+            };
+
+        let mut s3 = Self {
+            client,
+            parts,
+            sdk,
+            local,
+            target: target.clone(),
+            read_only: config.read_only,
+            encryption,
+            keys: None,
+            retry,
+            retries: AtomicU64::new(0),
+            limiter: Arc::new(RateLimiter::new(None, Vec::new())),
+            multipart: MultipartConfig::default(),
+            uploads: Mutex::new(UploadLog::default()),
+            persist_uploads: false,
+        };
+        s3.keys = s3.unlock(config).await?;
+        Ok(s3)
+    }
+
+    /// Derives the keys of an encrypted target, checking them against its key record. Giving a
+    /// key for a target which has no record sets up encryption, unless the target already holds
+    /// an unencrypted database.
+    async fn unlock(&self, config: &ClientConfig) -> anyhow::Result<Option<Keys>> {
+        let record = match self.read_object(KEY_OBJECT).await {
+            Ok(record) => Some(record),
+            Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::NotFound(_))) => None,
+            Err(err) => return Err(err),
+        };
+        match (record, &config.key) {
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(Error::Config(format!(
+                "{} is encrypted; pass --key-file or --passphrase",
+                self.target
+            ))
+            .into()),
+            (Some(record), Some(source)) => {
+                Keys::unlock(&record, source, config.keyed_hashes).map(Some)
+            }
+            (None, Some(source)) => {
+                if self.read_only {
+                    return Err(Error::Config(format!(
+                        "{} is not encrypted, and encryption can't be set up in read-only mode",
+                        self.target
+                    ))
+                    .into());
+                }
+                if self.key_exists("_s3b_db/entries.jsonl").await? {
+                    return Err(Error::Conflict(format!(
+                        "{} already holds unencrypted backups; encryption can only be set up for a new target",
+                        self.target
+                    ))
+                    .into());
+                }
+                let (record, keys) = Keys::create(source, config.keyed_hashes)?;
+                self.retry
+                    .retry(&self.retries, || async {
+                        self.client
+                            .put(
+                                &ObjectPath::from(KEY_OBJECT),
+                                PutPayload::from(record.clone()),
+                            )
+                            .await
+                            .map(|_| ())
+                            .map_err(|err| Error::from(err).into())
+                    })
+                    .await?;
+                println!("Set up client-side encryption for {}.", self.target);
+                Ok(Some(keys))
+            }
         }
     }
 
+    /// Limits the rate of uploads and downloads made through this client.
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = Arc::new(limiter);
+        self
+    }
+
+    /// Sets how large files are split into parts when uploaded through this client.
+    pub fn with_multipart(mut self, multipart: MultipartConfig) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Resumes multipart uploads recorded in `log`, and records the progress of new ones to disk.
+    pub fn with_upload_log(mut self, log: UploadLog) -> Self {
+        self.uploads = Mutex::new(log);
+        self.persist_uploads = true;
+        self
+    }
+
+    /// Whether objects and the database are encrypted before they are uploaded.
+    pub fn is_encrypted(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// The key which hashes of content are keyed with, if the target keys its hashes.
+    pub fn hash_key(&self) -> Option<&[u8; 32]> {
+        self.keys.as_ref().and_then(Keys::hash_key)
+    }
+
+    /// The name of the object holding the entry at `key`. An encrypted target stores entries
+    /// under names which don't reveal their keys; s3b's own objects keep their names.
+    pub fn object_key(&self, key: &str) -> String {
+        match &self.keys {
+            Some(keys) if !key.starts_with("_s3b_") => keys.object_name(key),
+            _ => key.to_string(),
+        }
+    }
+
+    /// Hashes content as the database records it.
+    fn hasher(&self) -> blake3::Hasher {
+        match self.hash_key() {
+            Some(key) => blake3::Hasher::new_keyed(key),
+            None => blake3::Hasher::new(),
+        }
+    }
+
+    /// The number of times an operation has been retried by this client.
+    pub fn retry_count(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub async fn key_exists(&self, key: &str) -> anyhow::Result<bool> {
+        let location = ObjectPath::from(self.object_key(key));
+        self.retry
+            .retry(&self.retries, || async {
+                match self.client.head(&location).await {
+                    Ok(_) => Ok(true),
+                    Err(object_store::Error::NotFound { path: _, source: _ }) => Ok(false),
+                    Err(err) => Err(Error::from(err).into()),
+                }
+            })
+            .await
+    }
+
+    /// Returns the ETag of the object at the key, or None if there is no such object.
+    pub async fn e_tag(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let location = ObjectPath::from(self.object_key(key));
+        self.retry
+            .retry(&self.retries, || async {
+                match self.client.head(&location).await {
+                    Ok(meta) => Ok(meta.e_tag),
+                    Err(object_store::Error::NotFound { path: _, source: _ }) => Ok(None),
+                    Err(err) => Err(Error::from(err).into()),
+                }
+            })
+            .await
+    }
+
+    /// Uploads a file or every file under a directory, keyed by their paths.
     pub async fn put(&self, path: &Path) -> anyhow::Result<()> {
         if path.is_file() {
-            self.put_one(path).await
+            self.put_one(path, path_to_key(path)?, None, None)
+                .await
+                .map(|_| ())
         } else if path.is_dir() {
             for entry in WalkDir::new(path).min_depth(1) {
-                let entry = entry.unwrap();
+                let entry = entry?;
                 let entry = entry.path();
                 if entry.is_file() {
-                    self.put_one(entry).await?;
+                    self.put_one(entry, path_to_key(entry)?, None, None).await?;
                 }
             }
             Ok(())
@@ -83,10 +649,8 @@ impl S3 {
     }
 
     pub async fn get(&self, key: &str) -> anyhow::Result<()> {
-        let last_char = key.get(key.len() - 1..key.len()).unwrap();
-        if last_char == "/" {
-            let mut list_stream = self.client.list(Some(&ObjectPath::from(key)));
-            while let Some(meta) = list_stream.next().await.transpose().unwrap() {
+        if key.ends_with('/') {
+            for meta in self.list_objects(Some(key)).await? {
                 self.get_one(meta.location.to_string().as_str()).await?
             }
         } else {
@@ -95,89 +659,1206 @@ impl S3 {
         Ok(())
     }
 
+    /// Lists every object in the bucket, or those under `prefix` if given.
+    pub async fn list_objects(&self, prefix: Option<&str>) -> anyhow::Result<Vec<ObjectMeta>> {
+        self.retry
+            .retry(&self.retries, || async {
+                self.client
+                    .list(prefix.map(ObjectPath::from).as_ref())
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .map_err(|err| Error::from(err).into())
+            })
+            .await
+    }
+
+    /// Downloads an object and returns the hash of its content as the database records it,
+    /// without writing it to disk. Content compressed with `codec` is decompressed first, and
+    /// the content of a packed object is read from its range of the pack.
+    pub async fn hash_object(
+        &self,
+        key: &str,
+        codec: Option<Codec>,
+        pack: Option<&PackRange>,
+    ) -> anyhow::Result<String> {
+        self.retry
+            .retry(&self.retries, || async {
+                let (hash, _) = self.read_content(key, codec, pack, std::io::sink()).await?;
+                Ok(hash)
+            })
+            .await
+    }
+
+    /// Streams the content of an object into `out`, decrypting and decompressing it as needed,
+    /// and returns the hash of the content along with `out`. The content of a packed object is
+    /// fetched with a ranged GET of the pack.
+    async fn read_content<W: Write>(
+        &self,
+        key: &str,
+        codec: Option<Codec>,
+        pack: Option<&PackRange>,
+        out: W,
+    ) -> anyhow::Result<(String, W)> {
+        let mut decoder = Decoder::new(codec, out, self.hasher())?;
+        let result = match pack {
+            // an empty range can't be requested
+            Some(pack) if pack.length == 0 => return decoder.finish(),
+            Some(pack) => {
+                let range = pack.offset as usize..(pack.offset + pack.length) as usize;
+                let options = GetOptions {
+                    range: Some(GetRange::Bounded(range)),
+                    ..Default::default()
+                };
+                self.client
+                    .get_opts(&ObjectPath::from(pack.pack_key.as_str()), options)
+                    .await
+            }
+            None => {
+                self.client
+                    .get(&ObjectPath::from(self.object_key(key)))
+                    .await
+            }
+        }
+        .map_err(Error::from)?;
+        let mut decryptor = self.keys.as_ref().map(Keys::decryptor);
+        let mut stream = result.into_stream();
+        while let Some(chunk) = stream.next().await.transpose().map_err(Error::from)? {
+            self.limiter.acquire(chunk.len()).await;
+            match &mut decryptor {
+                Some(decryptor) => decoder.write(&decryptor.update(&chunk)?)?,
+                None => decoder.write(&chunk)?,
+            };
+        }
+        if let Some(decryptor) = decryptor {
+            decoder.write(&decryptor.finish()?)?;
+        }
+        decoder.finish()
+    }
+
     pub async fn delete(&self, path: &str) -> anyhow::Result<Vec<String>> {
+        self.check_writable()?;
         let mut deleted: Vec<String> = Vec::new();
-        let last_char = path.get(path.len() - 1..path.len()).unwrap();
-        if last_char == "/" {
-            let mut list_stream = self.client.list(Some(&ObjectPath::from(path)));
-            while let Some(meta) = list_stream.next().await.transpose().unwrap() {
+        if path.ends_with('/') && self.keys.is_some() {
+            return Err(Error::Config(format!(
+                "objects in {} are stored under hidden names, so they can't be deleted by prefix",
+                self.target
+            ))
+            .into());
+        } else if path.ends_with('/') {
+            for meta in self.list_objects(Some(path)).await? {
                 self.delete_one(meta.location.to_string().as_str()).await?;
                 deleted.push(meta.location.to_string());
             }
         } else {
-            self.delete_one(path).await?;
+            self.delete_one(&self.object_key(path)).await?;
             deleted.push(path.into());
         }
 
         Ok(deleted)
     }
+
+    /// Lists multipart uploads in the bucket which have not been completed or aborted.
+    pub async fn list_multipart_uploads(&self) -> anyhow::Result<Vec<PendingUpload>> {
+        let (sdk, bucket_name) = match (&self.sdk, &self.target) {
+            (Some(sdk), Target::S3(bucket_name)) => (sdk, bucket_name),
+            (_, Target::Gcs(_) | Target::Azure(_)) => {
+                return Err(Error::Config(format!(
+                    "listing multipart uploads is not supported for {}; use the provider's lifecycle rules to clean them up",
+                    self.target
+                ))
+                .into())
+            }
+            _ => {
+                return match &self.local {
+                    Some(local) => local.list_uploads(),
+                    // uploads to memory don't outlive the command
+                    None => Ok(Vec::new()),
+                }
+            }
+        };
+        let mut pending = Vec::new();
+        let mut key_marker: Option<String> = None;
+        let mut upload_id_marker: Option<String> = None;
+        loop {
+            let output = self
+                .retry
+                .retry(&self.retries, || async {
+                    sdk.list_multipart_uploads()
+                        .bucket(bucket_name)
+                        .set_key_marker(key_marker.clone())
+                        .set_upload_id_marker(upload_id_marker.clone())
+                        .send()
+                        .await
+                        .map_err(|err| sdk_error(err).into())
+                })
+                .await?;
+
+            for upload in output.uploads() {
+                if let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) {
+                    pending.push(PendingUpload {
+                        key: key.to_string(),
+                        upload_id: upload_id.to_string(),
+                        initiated: upload
+                            .initiated()
+                            .and_then(|t| DateTime::<Utc>::from_timestamp(t.secs(), 0)),
+                    });
+                }
+            }
+
+            if !output.is_truncated().unwrap_or(false) {
+                break;
+            }
+            key_marker = output.next_key_marker().map(String::from);
+            upload_id_marker = output.next_upload_id_marker().map(String::from);
+        }
+
+        Ok(pending)
+    }
+
+    /// Checks whether an object is archived and whether it is being restored. Objects in targets
+    /// other than S3 are always available.
+    pub async fn archive_state(&self, key: &str) -> anyhow::Result<ArchiveState> {
+        let (sdk, bucket_name) = match (&self.sdk, &self.target) {
+            (Some(sdk), Target::S3(bucket_name)) => (sdk, bucket_name),
+            _ => return Ok(ArchiveState::Available),
+        };
+        let encryption = self.encryption.as_ref();
+        let output = self
+            .retry
+            .retry(&self.retries, || async {
+                sdk.head_object()
+                    .bucket(bucket_name)
+                    .key(self.object_key(key))
+                    .set_sse_customer_algorithm(encryption.and_then(Encryption::customer_algorithm))
+                    .set_sse_customer_key(encryption.and_then(Encryption::customer_key))
+                    .set_sse_customer_key_md5(encryption.and_then(Encryption::customer_key_md5))
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error(err).into())
+            })
+            .await?;
+        Ok(archive_state(
+            output.storage_class(),
+            output.archive_status().is_some(),
+            output.restore(),
+        ))
+    }
+
+    /// Requests a temporary copy of an archived object, kept for `days`. Objects archived by
+    /// Intelligent-Tiering are moved back to a frequent access tier instead, so `days` is unused.
+    pub async fn request_restore(
+        &self,
+        key: &str,
+        class: &str,
+        tier: Tier,
+        days: i32,
+    ) -> anyhow::Result<()> {
+        let (sdk, bucket_name) = self.sdk_for("restores")?;
+        let request = restore_request(class, tier, days)?;
+        self.retry
+            .retry(&self.retries, || async {
+                match sdk
+                    .restore_object()
+                    .bucket(bucket_name)
+                    .key(self.object_key(key))
+                    .restore_request(request.clone())
+                    .send()
+                    .await
+                {
+                    Ok(_) => Ok(()),
+                    Err(err) if err.code() == Some("RestoreAlreadyInProgress") => Ok(()),
+                    Err(err) => Err(sdk_error(err).into()),
+                }
+            })
+            .await
+    }
+
+    /// Downloads an object to a file, decrypting and decompressing it as needed, and replaces
+    /// any file at `dest` once the download is complete and its content matches the hash
+    /// `expected`.
+    pub async fn download(
+        &self,
+        key: &str,
+        dest: &Path,
+        expected: &str,
+        codec: Option<Codec>,
+        pack: Option<&PackRange>,
+    ) -> anyhow::Result<()> {
+        let mut partial = dest.as_os_str().to_owned();
+        partial.push(".s3b-partial");
+        let partial = PathBuf::from(partial);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let hash = self
+            .retry
+            .retry(&self.retries, || async {
+                let file = File::create(&partial)?;
+                let (hash, file) = self.read_content(key, codec, pack, file).await?;
+                file.sync_all()?;
+                Ok(hash)
+            })
+            .await;
+        match hash {
+            Ok(hash) if hash == expected => {
+                std::fs::rename(&partial, dest)?;
+                Ok(())
+            }
+            Ok(hash) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(Error::Integrity(format!(
+                    "{} has hash {}, but the database expected {}",
+                    key, hash, expected
+                ))
+                .into())
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn abort_multipart(&self, key: &str, upload_id: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
+        self.retry
+            .retry(&self.retries, || async {
+                let id = upload_id.to_string();
+                match self
+                    .parts
+                    .abort_multipart(&ObjectPath::from(key), &id)
+                    .await
+                {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(Error::from(err).into()),
+                }
+            })
+            .await
+    }
 }
 
 impl S3 {
-    async fn put_one(&self, path: &Path) -> anyhow::Result<()> {
+    /// Uploads a single file to the given key, with the given storage class if one is chosen,
+    /// compressing it with `codec` if that makes it smaller.
+    pub async fn put_one(
+        &self,
+        path: &Path,
+        key: &str,
+        storage_class: Option<&str>,
+        codec: Option<Codec>,
+    ) -> anyhow::Result<Uploaded> {
+        self.check_writable()?;
         if !path.is_file() {
             return Err(anyhow!("{:?} is not a file", path));
         }
 
-        let metadata = match std::fs::metadata(&path) {
+        let metadata = match std::fs::metadata(path) {
             Ok(m) => m,
-            Err(_err) => panic!("could not stat {:?}", &path),
+            Err(err) => return Err(anyhow!("could not stat {:?}: {}", &path, err)),
         };
-        let part_size = 50_000_000u64;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
 
-        if metadata.len() <= part_size {
-            let bytes = read_file_to_bytes(path)?;
-            let payload = PutPayload::from_bytes(bytes.into());
-            if let Err(err) = self
-                .client
-                .put(&ObjectPath::from(path.to_str().unwrap()), payload)
-                .await
-            {
-                return Err(err.into());
+        // compressed content is staged in a temporary file, which is uploaded in place of the file
+        let staged = match codec {
+            Some(codec) => {
+                let staged = staging_path(key);
+                let (hash, len) = codec.compress(path, &staged, self.hasher())?;
+                if len < metadata.len() {
+                    Some((staged, codec, hash, len))
+                } else {
+                    let _ = std::fs::remove_file(&staged);
+                    None
+                }
             }
-        } else {
-            let upload = self
-                .client
-                .put_multipart(&ObjectPath::from(path.to_str().unwrap()))
-                .await
-                .unwrap();
-            let mut writer = WriteMultipart::new(upload);
-            match File::open(path) {
-                Ok(mut file) => {
-                    for i in 0u64..(metadata.len() as f64 / part_size as f64).ceil() as u64 {
-                        let num_bytes = std::cmp::min(part_size, metadata.len() - (i * part_size));
-                        let mut buf = Vec::new();
-                        buf.resize(num_bytes as usize, 0);
-                        file.read_exact(&mut buf).unwrap();
-                        writer.write(&buf);
+            None => None,
+        };
+        let (source, len) = match &staged {
+            Some((staged, _, _, len)) => (staged.as_path(), *len),
+            None => (path, metadata.len()),
+        };
+        let res = self
+            .retry
+            .retry(&self.retries, || {
+                self.upload(source, key, len, modified, storage_class)
+            })
+            .await;
+        let res = match staged {
+            Some((staged, codec, hash, _)) => {
+                let _ = std::fs::remove_file(staged);
+                // the hash is of the content, not of what was uploaded
+                res.map(|uploaded| Uploaded {
+                    hash,
+                    codec: Some(codec),
+                    ..uploaded
+                })
+            }
+            None => res,
+        };
+        if res.is_err() && !self.persist_uploads {
+            // the upload can't be resumed, so don't leave its parts behind
+            self.abort_upload(key).await;
+        }
+        res
+    }
+
+    /// Uploads small files together as one pack object at `pack_key`, with a single request.
+    /// Each file is compressed with its codec if that makes it smaller, and encrypted on its
+    /// own, so that its range of the pack can be read back by itself. Returns what was uploaded
+    /// for each file, in order.
+    pub async fn put_pack(
+        &self,
+        pack_key: &str,
+        files: &[(&Path, &str, Option<Codec>)],
+        storage_class: Option<&str>,
+    ) -> anyhow::Result<Vec<Uploaded>> {
+        self.check_writable()?;
+        let mut builder = PackBuilder::new();
+        let mut members = Vec::with_capacity(files.len());
+        for &(path, key, codec) in files {
+            if !path.is_file() {
+                return Err(anyhow!("{:?} is not a file", path));
+            }
+            let modified = std::fs::metadata(path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)?
+                .as_secs();
+            let content = read_file_to_bytes(path)?;
+            let hash = self.hasher().update(&content).finalize().to_string();
+            let (content, codec) = match codec {
+                Some(codec) => {
+                    let compressed = codec.compress_bytes(&content)?;
+                    match compressed.len() < content.len() {
+                        true => (compressed, Some(codec)),
+                        false => (content, None),
                     }
-                    writer.finish().await.unwrap();
                 }
-                Err(err) => return Err(err.into()),
+                None => (content, None),
+            };
+            let content = match &self.keys {
+                Some(keys) => keys.sealer()?.seal_object(&content),
+                None => content,
+            };
+            let (offset, length) = builder.append(&self.object_key(key), modified, &content)?;
+            members.push((
+                hash,
+                codec,
+                PackRange {
+                    pack_key: pack_key.to_string(),
+                    offset,
+                    length,
+                },
+            ));
+        }
+        let bytes = builder.finish()?;
+        let (e_tag, checksum) = self
+            .retry
+            .retry(&self.retries, || {
+                self.put_bytes(pack_key, bytes.clone(), storage_class)
+            })
+            .await?;
+        Ok(members
+            .into_iter()
+            .map(|(hash, codec, pack)| Uploaded {
+                size: pack.length,
+                hash,
+                e_tag: e_tag.clone(),
+                checksum: checksum.clone(),
+                codec,
+                pack: Some(pack),
+            })
+            .collect())
+    }
+
+    async fn upload(
+        &self,
+        path: &Path,
+        key: &str,
+        len: u64,
+        modified: u64,
+        storage_class: Option<&str>,
+    ) -> anyhow::Result<Uploaded> {
+        let part_size = self
+            .multipart_config()
+            .upload_part_size(len, self.keys.is_some())?;
+        let stored_len = match self.keys {
+            Some(_) => crypto::sealed_len(len),
+            None => len,
+        };
+
+        if len <= part_size && stored_len <= MAX_PUT_SIZE {
+            let bytes = read_file_to_bytes(path)?;
+            let hash = self.hasher().update(&bytes).finalize().to_string();
+            let bytes = match &self.keys {
+                Some(keys) => keys.sealer()?.seal_object(&bytes),
+                None => bytes,
+            };
+            let size = bytes.len() as u64;
+            let (e_tag, checksum) = self.put_bytes(key, bytes, storage_class).await?;
+            Ok(Uploaded {
+                size,
+                hash,
+                e_tag,
+                checksum,
+                codec: None,
+                pack: None,
+            })
+        } else {
+            self.upload_multipart(path, key, len, modified, part_size, storage_class)
+                .await
+        }
+    }
+
+    /// Uploads an object with a single request, returning its ETag and the SHA-256 checksum
+    /// which the remote verified, if it returns one.
+    async fn put_bytes(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        storage_class: Option<&str>,
+    ) -> anyhow::Result<(Option<String>, Option<String>)> {
+        // object_store doesn't return the checksum which S3 verified, so S3 uploads go through
+        // the SDK
+        if self.sdk.is_some() {
+            let checksum = BASE64_STANDARD.encode(Sha256::digest(&bytes));
+            return self
+                .put_object_as(key, bytes, &checksum, storage_class)
+                .await;
+        }
+        self.limiter.acquire(bytes.len()).await;
+        let payload = PutPayload::from_bytes(bytes.into());
+        match self
+            .client
+            .put(&ObjectPath::from(self.object_key(key)), payload)
+            .await
+        {
+            Ok(res) => Ok((res.e_tag, None)),
+            Err(err) => Err(Error::from(err).into()),
+        }
+    }
+
+    /// Uploads a file in parts, continuing a previous upload of the same file if one was recorded.
+    async fn upload_multipart(
+        &self,
+        path: &Path,
+        key: &str,
+        len: u64,
+        modified: u64,
+        part_size: u64,
+        storage_class: Option<&str>,
+    ) -> anyhow::Result<Uploaded> {
+        let location = ObjectPath::from(self.object_key(key));
+        let resumable = self
+            .uploads
+            .lock()
+            .unwrap()
+            .get(&self.target, key)
+            .cloned()
+            .filter(|state| {
+                state.len == len
+                    && state.modified == modified
+                    && state.part_size == part_size
+                    && state.storage_class.as_deref() == storage_class
+                    && state.nonce.is_some() == self.keys.is_some()
+            });
+        let state = match resumable {
+            Some(state) => state,
+            None => {
+                // the file has changed since the recorded upload started, so start over
+                self.abort_upload(key).await;
+                let upload_id = match storage_class {
+                    Some(class) => self.create_multipart_as(key, class).await?,
+                    None => self
+                        .parts
+                        .create_multipart(&location)
+                        .await
+                        .map_err(Error::from)?,
+                };
+                let state = MultipartState {
+                    upload_id,
+                    len,
+                    modified,
+                    part_size,
+                    parts: vec![None; len.div_ceil(part_size) as usize],
+                    storage_class: storage_class.map(String::from),
+                    nonce: match &self.keys {
+                        Some(keys) => Some(keys.sealer()?.nonce()),
+                        None => None,
+                    },
+                };
+                self.uploads
+                    .lock()
+                    .unwrap()
+                    .insert(&self.target, key, state.clone());
+                self.write_uploads()?;
+                state
+            }
+        };
+
+        // parts which were uploaded by an earlier attempt are only read to hash them
+        let hasher = Mutex::new(PartHasher::new(self.hasher()));
+        let sealer = self
+            .keys
+            .as_ref()
+            .zip(state.nonce)
+            .map(|(keys, nonce)| keys.sealer_with_nonce(nonce));
+        let num_parts = state.parts.len();
+        let parts = state
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(idx, part)| Ok((idx, part.is_some())));
+        // only `concurrency` parts are read into memory at once
+        futures::stream::iter(parts)
+            .try_for_each_concurrent(self.multipart_config().concurrency, |(idx, uploaded)| {
+                let seal = sealer.as_ref().map(|sealer| (sealer, idx + 1 == num_parts));
+                self.upload_part(
+                    path,
+                    key,
+                    &state.upload_id,
+                    idx,
+                    part_size,
+                    uploaded,
+                    seal,
+                    &hasher,
+                )
+            })
+            .await?;
+        let hash = hasher.into_inner().unwrap().finalize(state.parts.len())?;
+
+        let parts = match self.uploads.lock().unwrap().get(&self.target, key) {
+            Some(state) => state.parts.iter().flatten().cloned().collect::<Vec<_>>(),
+            None => return Err(anyhow!("multipart upload of {} is no longer tracked", key)),
+        };
+        // object_store neither returns the checksum which S3 verified nor sends an SSE-C key on
+        // completion, so S3 uploads are completed through the SDK
+        let (e_tag, checksum) = match &self.sdk {
+            Some(_) => {
+                self.complete_multipart_as(key, &state.upload_id, &parts)
+                    .await?
+            }
+            None => {
+                let parts = parts
+                    .into_iter()
+                    .map(|part| PartId {
+                        content_id: part.content_id,
+                    })
+                    .collect();
+                let res = self
+                    .parts
+                    .complete_multipart(&location, &state.upload_id, parts)
+                    .await
+                    .map_err(Error::from)?;
+                (res.e_tag, None)
+            }
+        };
+        self.uploads.lock().unwrap().remove(&self.target, key);
+        self.write_uploads()?;
+
+        Ok(Uploaded {
+            size: match sealer {
+                Some(_) => crypto::sealed_len(len),
+                None => len,
+            },
+            hash,
+            e_tag,
+            checksum,
+            codec: None,
+            pack: None,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_part(
+        &self,
+        path: &Path,
+        key: &str,
+        upload_id: &String,
+        idx: usize,
+        part_size: u64,
+        uploaded: bool,
+        seal: Option<(&Sealer, bool)>,
+        hasher: &Mutex<PartHasher>,
+    ) -> anyhow::Result<()> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(idx as u64 * part_size))?;
+        let mut part = Vec::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        while (part.len() as u64) < part_size {
+            let max = std::cmp::min(CHUNK_SIZE as u64, part_size - part.len() as u64) as usize;
+            let num_bytes = file.read(&mut buf[..max])?;
+            if num_bytes == 0 {
+                break;
+            }
+            if !uploaded && !self.paces_bodies() {
+                self.limiter.acquire(num_bytes).await;
+            }
+            part.extend_from_slice(&buf[..num_bytes]);
+        }
+
+        let payload = PutPayload::from(part);
+        hasher.lock().unwrap().add(idx, payload.clone());
+        if uploaded {
+            return Ok(());
+        }
+        let payload = match seal {
+            Some((sealer, last)) => {
+                let plaintext = payload.iter().flatten().copied().collect::<Vec<_>>();
+                PutPayload::from(sealer.seal(&plaintext, idx as u64 * part_size / BLOCK_SIZE, last))
+            }
+            None => payload,
+        };
+
+        let sha256 = payload
+            .iter()
+            .fold(Sha256::new(), |digest, chunk| digest.chain_update(chunk))
+            .finalize()
+            .to_vec();
+        let part_id = match self.paces_bodies() {
+            true => {
+                self.upload_part_as(key, upload_id, idx, payload.into(), &sha256)
+                    .await?
             }
+            false => self
+                .parts
+                .put_part(
+                    &ObjectPath::from(self.object_key(key)),
+                    upload_id,
+                    idx,
+                    payload,
+                )
+                .await
+                .map_err(Error::from)?,
+        };
+        if let Some(state) = self.uploads.lock().unwrap().get_mut(&self.target, key) {
+            state.parts[idx] = Some(UploadedPart {
+                content_id: part_id.content_id,
+                sha256,
+            });
+        }
+        self.write_uploads()
+    }
+
+    /// Aborts the recorded multipart upload for `key`, if any. Failures are ignored, since
+    /// `s3b gc multipart` can clean up any upload left behind.
+    async fn abort_upload(&self, key: &str) {
+        let state = self.uploads.lock().unwrap().remove(&self.target, key);
+        if let Some(state) = state {
+            let _ = self
+                .parts
+                .abort_multipart(&ObjectPath::from(self.object_key(key)), &state.upload_id)
+                .await;
+            let _ = self.write_uploads();
+        }
+    }
+
+    /// Uploads an object through the SDK, which can set a storage class, pace the request body
+    /// and return the checksum which S3 verified, none of which object_store can do.
+    async fn put_object_as(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        checksum: &str,
+        class: Option<&str>,
+    ) -> anyhow::Result<(Option<String>, Option<String>)> {
+        let (sdk, bucket_name) = self.sdk_for("storage classes")?;
+        let encryption = self.encryption.as_ref();
+        let output = sdk
+            .put_object()
+            .bucket(bucket_name)
+            .key(self.object_key(key))
+            .set_storage_class(class.map(StorageClass::from))
+            .content_length(bytes.len() as i64)
+            .checksum_sha256(checksum)
+            .set_server_side_encryption(encryption.and_then(Encryption::server_side_encryption))
+            .set_ssekms_key_id(encryption.and_then(Encryption::kms_key_id))
+            .set_sse_customer_algorithm(encryption.and_then(Encryption::customer_algorithm))
+            .set_sse_customer_key(encryption.and_then(Encryption::customer_key))
+            .set_sse_customer_key_md5(encryption.and_then(Encryption::customer_key_md5))
+            .body(self.body(bytes))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok((
+            output.e_tag().map(String::from),
+            output.checksum_sha256().map(String::from),
+        ))
+    }
+
+    /// Starts a multipart upload with a storage class through the SDK; its parts are uploaded
+    /// through object_store as usual.
+    async fn create_multipart_as(&self, key: &str, class: &str) -> anyhow::Result<String> {
+        let (sdk, bucket_name) = self.sdk_for("storage classes")?;
+        let encryption = self.encryption.as_ref();
+        let output = sdk
+            .create_multipart_upload()
+            .bucket(bucket_name)
+            .key(self.object_key(key))
+            .storage_class(StorageClass::from(class))
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .set_server_side_encryption(encryption.and_then(Encryption::server_side_encryption))
+            .set_ssekms_key_id(encryption.and_then(Encryption::kms_key_id))
+            .set_sse_customer_algorithm(encryption.and_then(Encryption::customer_algorithm))
+            .set_sse_customer_key(encryption.and_then(Encryption::customer_key))
+            .set_sse_customer_key_md5(encryption.and_then(Encryption::customer_key_md5))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        output
+            .upload_id()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("no upload ID was returned for {}", key))
+    }
+
+    /// Uploads a part through the SDK with a paced body, returning its ID as object_store would
+    /// record it, so that the upload is completed as usual.
+    async fn upload_part_as(
+        &self,
+        key: &str,
+        upload_id: &str,
+        idx: usize,
+        bytes: Bytes,
+        sha256: &[u8],
+    ) -> anyhow::Result<PartId> {
+        let (sdk, bucket_name) = self.sdk_for("paced uploads")?;
+        let encryption = self.encryption.as_ref();
+        let output = sdk
+            .upload_part()
+            .bucket(bucket_name)
+            .key(self.object_key(key))
+            .upload_id(upload_id)
+            .part_number(idx as i32 + 1)
+            .content_length(bytes.len() as i64)
+            .checksum_sha256(BASE64_STANDARD.encode(sha256))
+            .set_sse_customer_algorithm(encryption.and_then(Encryption::customer_algorithm))
+            .set_sse_customer_key(encryption.and_then(Encryption::customer_key))
+            .set_sse_customer_key_md5(encryption.and_then(Encryption::customer_key_md5))
+            .body(self.body(bytes))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        let e_tag = output
+            .e_tag()
+            .ok_or_else(|| anyhow!("no ETag was returned for part {} of {}", idx + 1, key))?;
+        Ok(PartId {
+            content_id: part_content_id(e_tag, output.checksum_sha256()),
+        })
+    }
+
+    /// A request body for the SDK, paced by the rate limiter while it is limited.
+    fn body(&self, bytes: impl Into<Bytes>) -> ByteStream {
+        match self.limiter.is_limited() {
+            true => ByteStream::from_body_1_x(PacedBody::new(bytes, self.limiter.clone())),
+            false => ByteStream::from(bytes.into()),
+        }
+    }
+
+    /// Whether uploads are paced as their request bodies are sent, which needs the SDK. Other
+    /// targets only wait before each request.
+    fn paces_bodies(&self) -> bool {
+        self.limiter.is_limited() && matches!((&self.sdk, &self.target), (Some(_), Target::S3(_)))
+    }
+
+    /// How files are split into parts. While the rate is limited, a target which can't pace
+    /// request bodies sends one part of the smallest size at a time, since each request goes
+    /// out at full speed.
+    fn multipart_config(&self) -> MultipartConfig {
+        match self.limiter.is_limited() && !self.paces_bodies() {
+            true => MultipartConfig {
+                part_size: MIN_PART_SIZE,
+                concurrency: 1,
+            },
+            false => self.multipart.clone(),
+        }
+    }
+
+    /// Completes a multipart upload through the SDK, returning the ETag and the checksum which
+    /// S3 verified. object_store returns no checksum, and doesn't send an SSE-C key when
+    /// completing an upload, which S3 requires.
+    async fn complete_multipart_as(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> anyhow::Result<(Option<String>, Option<String>)> {
+        let (sdk, bucket_name) = self.sdk_for("multipart completions")?;
+        let encryption = self.encryption.as_ref();
+        let parts = parts
+            .iter()
+            .enumerate()
+            .map(|(idx, part)| {
+                CompletedPart::builder()
+                    .part_number(idx as i32 + 1)
+                    .e_tag(part_e_tag(&part.content_id))
+                    .checksum_sha256(BASE64_STANDARD.encode(&part.sha256))
+                    .build()
+            })
+            .collect();
+        let output = sdk
+            .complete_multipart_upload()
+            .bucket(bucket_name)
+            .key(self.object_key(key))
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .set_sse_customer_algorithm(encryption.and_then(Encryption::customer_algorithm))
+            .set_sse_customer_key(encryption.and_then(Encryption::customer_key))
+            .set_sse_customer_key_md5(encryption.and_then(Encryption::customer_key_md5))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok((
+            output.e_tag().map(String::from),
+            output.checksum_sha256().map(String::from),
+        ))
+    }
+
+    /// The SDK client and bucket name, for the S3 features which object_store does not support.
+    fn sdk_for(&self, feature: &str) -> anyhow::Result<(&aws_sdk_s3::Client, &str)> {
+        match (&self.sdk, &self.target) {
+            (Some(sdk), Target::S3(bucket_name)) => Ok((sdk, bucket_name)),
+            _ => Err(Error::Config(format!(
+                "{} are only supported for S3 targets, not {}",
+                feature, self.target
+            ))
+            .into()),
         }
+    }
 
+    fn check_writable(&self) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(Error::Config(format!(
+                "refusing to write to {} in read-only mode",
+                self.target
+            ))
+            .into());
+        }
         Ok(())
     }
 
-    async fn get_one(&self, path: &str) -> anyhow::Result<()> {
-        match self.client.get(&ObjectPath::from(path)).await {
-            Ok(result) => {
-                write_bytes_to_file(&Path::new(path), result.bytes().await.unwrap().as_ref())
-            }
-            Err(err) => Err(err.into()),
+    fn write_uploads(&self) -> anyhow::Result<()> {
+        if self.persist_uploads {
+            self.uploads.lock().unwrap().write()
+        } else {
+            Ok(())
         }
     }
 
+    async fn get_one(&self, path: &str) -> anyhow::Result<()> {
+        let bytes = self.read_object(path).await?;
+        let bytes = match &self.keys {
+            Some(keys) => crypto::decrypt(keys, &bytes)?,
+            None => bytes,
+        };
+        write_bytes_to_file(Path::new(path), &bytes)
+    }
+
+    /// Reads a whole object into memory as it is stored.
+    async fn read_object(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.retry
+            .retry(&self.retries, || async {
+                match self.client.get(&ObjectPath::from(key)).await {
+                    Ok(result) => {
+                        let mut bytes = Vec::new();
+                        let mut stream = result.into_stream();
+                        while let Some(chunk) =
+                            stream.next().await.transpose().map_err(Error::from)?
+                        {
+                            self.limiter.acquire(chunk.len()).await;
+                            bytes.extend_from_slice(&chunk);
+                        }
+                        Ok(bytes)
+                    }
+                    Err(err) => Err(Error::from(err).into()),
+                }
+            })
+            .await
+    }
+
     async fn delete_one(&self, key: &str) -> anyhow::Result<()> {
-        if let Err(err) = self.client.delete(&ObjectPath::from(key)).await {
-            return Err(err.into())
+        self.retry
+            .retry(&self.retries, || async {
+                match self.client.delete(&ObjectPath::from(key)).await {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(Error::from(err).into()),
+                }
+            })
+            .await
+    }
+}
+
+/// Hashes the parts of a multipart upload in order, although they are read concurrently. Parts
+/// which are read ahead of the next one to hash are held until it arrives.
+struct PartHasher {
+    hasher: blake3::Hasher,
+    next: usize,
+    pending: BTreeMap<usize, PutPayload>,
+}
+
+impl PartHasher {
+    fn new(hasher: blake3::Hasher) -> Self {
+        Self {
+            hasher,
+            next: 0,
+            pending: BTreeMap::new(),
         }
+    }
 
-        Ok(())
+    fn add(&mut self, idx: usize, part: PutPayload) {
+        self.pending.insert(idx, part);
+        while let Some(part) = self.pending.remove(&self.next) {
+            part.iter().for_each(|chunk| {
+                self.hasher.update(chunk);
+            });
+            self.next += 1;
+        }
+    }
+
+    fn finalize(self, num_parts: usize) -> anyhow::Result<String> {
+        if self.next != num_parts {
+            return Err(anyhow!("hashed {} of {} parts", self.next, num_parts));
+        }
+        Ok(self.hasher.finalize().to_string())
     }
 }
 
+/// Builds the object_store client for an S3 bucket, and an SDK client configured to match.
+async fn amazon_s3(
+    bucket_name: &str,
+    config: &ClientConfig,
+    encryption: Option<&Encryption>,
+) -> anyhow::Result<(AmazonS3, aws_sdk_s3::Client)> {
+    let mut loader = aws_config::from_env();
+    let mut default_region = DefaultRegionChain::builder();
+    if let Some(profile) = &config.profile {
+        loader = loader.profile_name(profile);
+        default_region = default_region.profile_name(profile);
+    }
+    if config.anonymous {
+        loader = loader.no_credentials();
+    }
+    let region_provider = RegionProviderChain::first_try(config.region.clone().map(Region::new))
+        .or_else(default_region.build())
+        .or_else("us-east-1");
+    let sdk_config = loader.region(region_provider).load().await;
+    let region = sdk_config
+        .region()
+        .ok_or_else(|| Error::Config("expected AWS region to be set".into()))?;
+    // path-style requests are what S3-compatible services expect at a custom endpoint
+    let path_style = config.path_style || config.endpoint.is_some();
+
+    let mut builder = AmazonS3Builder::new()
+        .with_region(region.as_ref())
+        .with_bucket_name(bucket_name)
+        .with_virtual_hosted_style_request(!path_style)
+        .with_allow_http(config.allow_http)
+        // the remote rejects any object or part whose content doesn't match this checksum
+        .with_checksum_algorithm(Checksum::SHA256)
+        .with_retry(no_retries());
+    builder = match encryption {
+        Some(Encryption::Aes256) => {
+            builder.with_config("aws_server_side_encryption".parse()?, "AES256")
+        }
+        Some(Encryption::Kms(Some(kms_key_id))) => builder.with_sse_kms_encryption(kms_key_id),
+        Some(Encryption::Kms(None)) => {
+            builder.with_config("aws_server_side_encryption".parse()?, "aws:kms")
+        }
+        Some(Encryption::Customer(key)) => {
+            builder.with_ssec_encryption(BASE64_STANDARD.encode(key))
+        }
+        None => builder,
+    };
+    if config.anonymous {
+        builder = builder.with_skip_signature(true);
+    } else {
+        let provider = sdk_config
+            .credentials_provider()
+            .ok_or_else(|| Error::Auth("no AWS credentials provider is configured".into()))?;
+        // load the credentials up front, so that missing credentials are reported before any work
+        let creds = provider
+            .provide_credentials()
+            .await
+            .map_err(|err| Error::Auth(format!("could not load AWS credentials: {}", err)))?;
+        builder = builder.with_credentials(Arc::new(SdkCredentials {
+            provider,
+            cached: Mutex::new(Some(creds)),
+        }));
+    }
+
+    let mut sdk = aws_sdk_s3::config::Builder::from(&sdk_config)
+        .force_path_style(path_style)
+        .retry_config(aws_config::retry::RetryConfig::disabled());
+
+    if let Some(ep) = &config.endpoint {
+        builder = builder.with_endpoint(ep);
+        sdk = sdk.endpoint_url(ep);
+    }
+
+    match builder.build() {
+        Ok(client) => Ok((client, aws_sdk_s3::Client::from_conf(sdk.build()))),
+        Err(err) => Err(Error::Config(err.to_string()).into()),
+    }
+}
+
+/// Supplies object_store with credentials from the AWS SDK, which resolves profiles, SSO and
+/// assumed roles. Temporary credentials are refreshed shortly before they expire, so that long
+/// pushes outlive them.
+#[derive(Debug)]
+struct SdkCredentials {
+    provider: SharedCredentialsProvider,
+    cached: Mutex<Option<Credentials>>,
+}
+
+#[async_trait]
+impl CredentialProvider for SdkCredentials {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        let refresh_at = SystemTime::now() + CREDENTIAL_REFRESH;
+        let cached = self.cached.lock().unwrap().clone();
+        let creds = match cached.filter(|c| c.expiry().is_none_or(|t| t > refresh_at)) {
+            Some(creds) => creds,
+            None => {
+                let creds = self.provider.provide_credentials().await.map_err(|err| {
+                    object_store::Error::Unauthenticated {
+                        path: String::new(),
+                        source: Box::new(err),
+                    }
+                })?;
+                *self.cached.lock().unwrap() = Some(creds.clone());
+                creds
+            }
+        };
+        Ok(Arc::new(AwsCredential {
+            key_id: creds.access_key_id().to_string(),
+            secret_key: creds.secret_access_key().to_string(),
+            token: creds.session_token().map(String::from),
+        }))
+    }
+}
+
+/// Checks that a storage class is one S3 knows, e.g. `GLACIER_IR` or `deep_archive`, and returns
+/// it as S3 spells it.
+pub fn check_storage_class(value: &str) -> anyhow::Result<String> {
+    let class = value.trim().to_ascii_uppercase();
+    if StorageClass::values().contains(&class.as_str()) {
+        Ok(class)
+    } else {
+        Err(Error::Config(format!(
+            "unknown storage class '{}'; expected one of {}",
+            value,
+            StorageClass::values().join(", ")
+        ))
+        .into())
+    }
+}
+
+/// Requests are retried by `RetryPolicy` instead, so that retries can be configured and counted.
+/// Whether an object with the given storage class, archive status and `x-amz-restore` header can
+/// be downloaded. The header looks like `ongoing-request="false", expiry-date="..."` once a
+/// restore has been requested.
+fn archive_state(
+    storage_class: Option<&StorageClass>,
+    archived_by_tiering: bool,
+    restore: Option<&str>,
+) -> ArchiveState {
+    let archived = matches!(
+        storage_class,
+        Some(StorageClass::Glacier | StorageClass::DeepArchive)
+    ) || archived_by_tiering;
+    match restore {
+        _ if !archived => ArchiveState::Available,
+        Some(restore) if restore.contains("ongoing-request=\"true\"") => ArchiveState::Restoring,
+        Some(_) => ArchiveState::Available,
+        None => ArchiveState::Archived(
+            storage_class
+                .map_or("INTELLIGENT_TIERING", |class| class.as_str())
+                .to_string(),
+        ),
+    }
+}
+
+/// The request to restore an object archived with `class`. Objects archived by
+/// Intelligent-Tiering are moved back rather than copied, so they are given no `days`.
+fn restore_request(class: &str, tier: Tier, days: i32) -> anyhow::Result<RestoreRequest> {
+    let mut request = RestoreRequest::builder()
+        .glacier_job_parameters(GlacierJobParameters::builder().tier(tier).build()?);
+    if class != "INTELLIGENT_TIERING" {
+        request = request.days(days);
+    }
+    Ok(request.build())
+}
+
+fn no_retries() -> RetryConfig {
+    RetryConfig {
+        max_retries: 0,
+        ..Default::default()
+    }
+}
+
+fn sdk_error<E>(err: aws_sdk_s3::error::SdkError<E>) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let msg = DisplayErrorContext(&err).to_string();
+    match err.code() {
+        Some("NoSuchBucket") => Error::NotFound("bucket does not exist".into()),
+        Some("NoSuchKey" | "NotFound") => Error::NotFound(msg),
+        Some("AccessDenied" | "InvalidAccessKeyId" | "SignatureDoesNotMatch" | "ExpiredToken") => {
+            Error::Auth(msg)
+        }
+        _ => Error::Network(msg),
+    }
+}
+
+/// The ETag of an uploaded part. object_store records the part's checksum along with its ETag
+/// as XML when checksums are enabled, as they are for S3 targets.
+fn part_e_tag(content_id: &str) -> String {
+    let e_tag = match content_id.split_once("<e_tag>") {
+        Some((_, rest)) => rest.split_once("</e_tag>").map_or(rest, |(e_tag, _)| e_tag),
+        None => content_id,
+    };
+    e_tag
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// The ID object_store records for an uploaded part, which holds its checksum along with its
+/// ETag when checksums are enabled; the inverse of [`part_e_tag`].
+fn part_content_id(e_tag: &str, checksum_sha256: Option<&str>) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    };
+    let checksum = checksum_sha256
+        .map(|checksum| format!("<checksum_sha256>{}</checksum_sha256>", escape(checksum)))
+        .unwrap_or_default();
+    format!(
+        "<PartMetadata><e_tag>{}</e_tag>{}</PartMetadata>",
+        escape(e_tag),
+        checksum
+    )
+}
+
+/// Where the compressed content of the file at `key` is staged while it is uploaded.
+fn staging_path(key: &str) -> PathBuf {
+    let name = blake3::hash(key.as_bytes()).to_string();
+    std::env::temp_dir().join(format!("s3b-{}-{}", std::process::id(), &name[..16]))
+}
+
+fn path_to_key(path: &Path) -> anyhow::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", &path))
+}
+
 fn read_file_to_bytes(path: &Path) -> anyhow::Result<Vec<u8>> {
     match File::open(path) {
         Ok(mut file) => {
@@ -192,10 +1873,319 @@ fn read_file_to_bytes(path: &Path) -> anyhow::Result<Vec<u8>> {
 fn write_bytes_to_file(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
     let mut buf = PathBuf::from(path);
     buf.pop();
-    std::fs::create_dir_all(buf).unwrap();
+    std::fs::create_dir_all(buf)?;
 
-    match File::options().write(true).create(true).open(path) {
+    match File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+    {
         Ok(mut file) => file.write_all(bytes).map_err(|err| err.into()),
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::BoxStream;
+    use object_store::{
+        GetResult, ListResult, MultipartUpload, PutMultipartOpts, PutOptions, PutResult,
+    };
+
+    use super::*;
+
+    /// Wraps a store, failing the first `failures` calls made through it as a dropped
+    /// connection would, to check that every operation is retried.
+    #[derive(Debug)]
+    struct FaultyStore {
+        inner: Arc<dyn ObjectStore>,
+        failures: AtomicU64,
+        calls: AtomicU64,
+    }
+
+    impl FaultyStore {
+        fn fault(&self) -> object_store::Result<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(object_store::Error::Generic {
+                    store: "faulty",
+                    source: "connection reset by peer".into(),
+                }),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    impl std::fmt::Display for FaultyStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Faulty({})", self.inner)
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for FaultyStore {
+        async fn put_opts(
+            &self,
+            location: &ObjectPath,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            self.fault()?;
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &ObjectPath,
+            opts: PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            self.fault()?;
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &ObjectPath,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.fault()?;
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+            self.fault()?;
+            self.inner.delete(location).await
+        }
+
+        fn list(
+            &self,
+            prefix: Option<&ObjectPath>,
+        ) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            match self.fault() {
+                Ok(()) => self.inner.list(prefix),
+                Err(err) => futures::stream::once(async { Err(err) }).boxed(),
+            }
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&ObjectPath>,
+        ) -> object_store::Result<ListResult> {
+            self.fault()?;
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+            self.fault()?;
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(
+            &self,
+            from: &ObjectPath,
+            to: &ObjectPath,
+        ) -> object_store::Result<()> {
+            self.fault()?;
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    /// An in-memory target holding `keys`, whose next `failures` calls fail.
+    async fn faulty_target(
+        keys: &[&str],
+        failures: u64,
+        max_attempts: u32,
+    ) -> (S3, Arc<FaultyStore>) {
+        let retry = RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            retry_on: vec!["network".into()],
+        };
+        let mut s3 = S3::new(&Target::Memory, &ClientConfig::default(), retry)
+            .await
+            .unwrap();
+        // a store of its own, rather than the one shared by memory:// targets
+        s3.client = Arc::new(InMemory::new());
+        for key in keys {
+            s3.client
+                .put(&ObjectPath::from(*key), PutPayload::from_static(b"content"))
+                .await
+                .unwrap();
+        }
+        let faulty = Arc::new(FaultyStore {
+            inner: s3.client.clone(),
+            failures: AtomicU64::new(failures),
+            calls: AtomicU64::new(0),
+        });
+        s3.client = faulty.clone();
+        (s3, faulty)
+    }
+
+    #[tokio::test]
+    async fn operations_are_retried() {
+        let (s3, faulty) = faulty_target(&["dir/a", "dir/b", "other"], 2, 5).await;
+        assert!(s3.key_exists("other").await.unwrap());
+        assert_eq!(s3.retry_count(), 2);
+
+        // listing a prefix to delete it is retried like any other request
+        faulty.failures.store(2, Ordering::Relaxed);
+        assert_eq!(s3.delete("dir/").await.unwrap(), vec!["dir/a", "dir/b"]);
+        assert_eq!(s3.retry_count(), 4);
+
+        faulty.failures.store(1, Ordering::Relaxed);
+        let keys = s3.list_objects(None).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].location.as_ref(), "other");
+        assert_eq!(s3.retry_count(), 5);
+    }
+
+    #[tokio::test]
+    async fn operations_give_up_after_max_attempts() {
+        let (s3, faulty) = faulty_target(&["dir/a"], 10, 3).await;
+        let err = s3.delete("dir/").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Network(_))
+        ));
+        assert_eq!(faulty.calls.load(Ordering::Relaxed), 3);
+        assert_eq!(s3.retry_count(), 2);
+    }
+
+    #[test]
+    fn part_e_tag_is_read_from_content_id() {
+        assert_eq!(part_e_tag("\"abc\""), "\"abc\"");
+        let content_id = "<PartMetadata><e_tag>&quot;abc&quot;</e_tag><checksum_sha256>AAA=</checksum_sha256></PartMetadata>";
+        assert_eq!(part_e_tag(content_id), "\"abc\"");
+        let content_id = part_content_id("\"a&b\"", Some("AAA="));
+        assert_eq!(
+            content_id,
+            "<PartMetadata><e_tag>&quot;a&amp;b&quot;</e_tag><checksum_sha256>AAA=</checksum_sha256></PartMetadata>"
+        );
+        assert_eq!(part_e_tag(&content_id), "\"a&b\"");
+    }
+
+    #[tokio::test]
+    async fn limited_targets_without_paced_bodies_send_one_small_part_at_a_time() {
+        let multipart = MultipartConfig {
+            part_size: 100 * 1024 * 1024,
+            concurrency: 8,
+        };
+        let s3 = S3::new(
+            &Target::Memory,
+            &ClientConfig::default(),
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap()
+        .with_multipart(multipart.clone());
+        assert!(!s3.paces_bodies());
+        assert_eq!(s3.multipart_config().part_size, multipart.part_size);
+        assert_eq!(s3.multipart_config().concurrency, multipart.concurrency);
+
+        let s3 = s3.with_rate_limit(RateLimiter::new(Some(1024 * 1024), Vec::new()));
+        assert!(!s3.paces_bodies());
+        assert_eq!(s3.multipart_config().part_size, MIN_PART_SIZE);
+        assert_eq!(s3.multipart_config().concurrency, 1);
+    }
+
+    #[test]
+    fn part_size_stays_within_s3_limits() {
+        let multipart = |part_size| MultipartConfig {
+            part_size,
+            concurrency: 1,
+        };
+        assert_eq!(multipart(MIN_PART_SIZE).part_size_for(1), MIN_PART_SIZE);
+        // grown to keep within 10,000 parts
+        let len = 100 * 1024 * 1024 * 1024;
+        assert_eq!(
+            multipart(MIN_PART_SIZE).part_size_for(len),
+            len.div_ceil(MAX_PARTS)
+        );
+        // but never beyond the largest part S3 accepts
+        assert_eq!(
+            multipart(MIN_PART_SIZE).part_size_for(1 << 50),
+            MAX_PART_SIZE
+        );
+        assert_eq!(
+            multipart(10 * 1024 * 1024 * 1024).part_size_for(1),
+            MAX_PART_SIZE
+        );
+    }
+
+    #[test]
+    fn encrypted_parts_stay_within_s3_limits() {
+        let multipart = MultipartConfig {
+            part_size: MAX_PART_SIZE,
+            concurrency: 1,
+        };
+        let part_size = multipart.upload_part_size(1 << 40, true).unwrap();
+        assert_eq!(part_size % BLOCK_SIZE, 0);
+        assert!(crypto::sealed_len(part_size) <= MAX_PART_SIZE);
+        // 10,000 parts of the largest size S3 accepts fit unencrypted, but not once sealing has
+        // shrunk the parts
+        let len = MAX_PARTS * MAX_PART_SIZE;
+        assert_eq!(
+            multipart.upload_part_size(len, false).unwrap(),
+            MAX_PART_SIZE
+        );
+        assert!(multipart.upload_part_size(len, true).is_err());
+        assert!(multipart.upload_part_size(len + 1, false).is_err());
+    }
+
+    #[test]
+    fn archive_state_from_restore_header() {
+        let glacier = Some(&StorageClass::Glacier);
+        assert_eq!(
+            archive_state(glacier, false, None),
+            ArchiveState::Archived("GLACIER".into())
+        );
+        assert_eq!(
+            archive_state(glacier, false, Some("ongoing-request=\"true\"")),
+            ArchiveState::Restoring
+        );
+        assert_eq!(
+            archive_state(
+                Some(&StorageClass::DeepArchive),
+                false,
+                Some("ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"")
+            ),
+            ArchiveState::Available
+        );
+        // a restore header on an object which is not archived changes nothing
+        assert_eq!(
+            archive_state(
+                Some(&StorageClass::Standard),
+                false,
+                Some("ongoing-request=\"true\"")
+            ),
+            ArchiveState::Available
+        );
+        assert_eq!(archive_state(None, false, None), ArchiveState::Available);
+        // Intelligent-Tiering reports archived objects through their archive status
+        assert_eq!(
+            archive_state(Some(&StorageClass::IntelligentTiering), true, None),
+            ArchiveState::Archived("INTELLIGENT_TIERING".into())
+        );
+        assert_eq!(
+            archive_state(None, true, None),
+            ArchiveState::Archived("INTELLIGENT_TIERING".into())
+        );
+    }
+
+    #[test]
+    fn restore_request_days() {
+        let request = restore_request("DEEP_ARCHIVE", Tier::Bulk, 3).unwrap();
+        assert_eq!(request.days(), Some(3));
+        assert_eq!(
+            request.glacier_job_parameters().map(|params| params.tier()),
+            Some(&Tier::Bulk)
+        );
+        let request = restore_request("INTELLIGENT_TIERING", Tier::Standard, 3).unwrap();
+        assert_eq!(request.days(), None);
+    }
+}
@@ -1,9 +1,30 @@
 use std::collections::HashMap;
 
 use anyhow::anyhow;
-use gluesql::prelude::{Glue, JsonStorage, Value};
+use gluesql::{
+    core::store::Store,
+    prelude::{Glue, JsonStorage, Payload, Value},
+};
 
-use crate::PlanEntry;
+use crate::{pack::PackRange, PlanEntry};
+
+/// Columns of the `entries` table, in order. Columns which were added after the table was first
+/// released are nullable; existing databases are migrated with NULL in those columns.
+const ENTRIES_COLUMNS: [(&str, &str); 13] = [
+    ("key", "TEXT PRIMARY KEY"),
+    ("hash", "TEXT"),
+    ("path", "TEXT"),
+    ("modified", "UINT64"),
+    ("etag", "TEXT NULL"),
+    ("checksum", "TEXT NULL"),
+    ("size", "UINT64 NULL"),
+    ("source", "TEXT NULL"),
+    ("storage_class", "TEXT NULL"),
+    ("codec", "TEXT NULL"),
+    ("pack_key", "TEXT NULL"),
+    ("pack_offset", "UINT64 NULL"),
+    ("pack_length", "UINT64 NULL"),
+];
 
 pub struct Sql {
     glue: Glue<JsonStorage>,
@@ -14,12 +35,14 @@ impl Sql {
         let storage = JsonStorage::new("_s3b_db").map_err(|e| anyhow!(e))?;
         let mut glue = Glue::new(storage);
 
-        let queries = r#"
-        CREATE TABLE IF NOT EXISTS entries (key TEXT PRIMARY KEY, hash TEXT, path TEXT, modified UINT64);
-        "#;
+        let queries = format!("CREATE TABLE IF NOT EXISTS entries ({});", column_defs());
 
         match glue.execute(queries).await {
-            Ok(_) => Ok(Self { glue }),
+            Ok(_) => {
+                let mut sql = Self { glue };
+                sql.migrate().await?;
+                Ok(sql)
+            }
             Err(err) => Err(anyhow!(err)),
         }
     }
@@ -34,14 +57,13 @@ impl Sql {
         self.select_entries(&query).await
     }
 
-    pub async fn put_entry(&mut self, entry: &PlanEntry) -> anyhow::Result<()> {
-        let query = format!(
-            "INSERT INTO entries VALUES ('{}', '{}', '{}', {});",
-            entry.key,
-            entry.hash,
-            entry.path.to_str().unwrap(),
-            entry.modified,
-        );
+    pub async fn put_entry(&mut self, entry: &EntriesRow) -> anyhow::Result<()> {
+        let values = entry
+            .values()
+            .iter()
+            .map(literal)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let query = format!("INSERT INTO entries VALUES ({});", values.join(", "));
 
         match self.glue.execute(query).await {
             Ok(_) => Ok(()),
@@ -49,13 +71,17 @@ impl Sql {
         }
     }
 
-    pub async fn update_entry(&mut self, entry: &PlanEntry) -> anyhow::Result<()> {
+    pub async fn update_entry(&mut self, entry: &EntriesRow) -> anyhow::Result<()> {
+        let assignments = ENTRIES_COLUMNS
+            .iter()
+            .zip(entry.values().iter())
+            .skip(1)
+            .map(|((name, _), value)| Ok(format!("{}={}", name, literal(value)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
         let query = format!(
-            "UPDATE entries SET hash='{}', path='{}', modified={} WHERE key='{}';",
-            entry.hash,
-            entry.path.to_str().unwrap(),
-            entry.modified,
-            entry.key,
+            "UPDATE entries SET {} WHERE key={};",
+            assignments.join(", "),
+            quote(&entry.key),
         );
 
         match self.glue.execute(query).await {
@@ -65,7 +91,7 @@ impl Sql {
     }
 
     pub async fn delete_entry_by_key(&mut self, key: &str) -> anyhow::Result<()> {
-        let query = format!("DELETE FROM entries WHERE key='{}';", key);
+        let query = format!("DELETE FROM entries WHERE key={};", quote(key));
         match self.glue.execute(query).await {
             Ok(_) => Ok(()),
             Err(err) => Err(anyhow!(err)),
@@ -78,15 +104,92 @@ impl Sql {
         match self.glue.execute(query).await {
             Ok(mut res) => {
                 let payload = res.remove(0);
-                Ok(payload
+                let rows = payload
                     .select()
-                    .unwrap()
+                    .ok_or_else(|| anyhow!("expected query to return rows: {}", query))?
                     .map(TryInto::<EntriesRow>::try_into)
-                    .collect::<anyhow::Result<_>>()?)
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(rows)
             }
             Err(err) => Err(anyhow!(err)),
         }
     }
+
+    /// Rebuilds the `entries` table if it is missing any columns, since JsonStorage does not
+    /// support ALTER TABLE.
+    async fn migrate(&mut self) -> anyhow::Result<()> {
+        let schema = self
+            .glue
+            .storage
+            .fetch_schema("entries")
+            .await
+            .map_err(|e| anyhow!(e))?;
+        let existing = schema
+            .and_then(|s| s.column_defs)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|c| c.name)
+            .collect::<Vec<_>>();
+        if ENTRIES_COLUMNS
+            .iter()
+            .all(|(name, _)| existing.iter().any(|c| c == name))
+        {
+            return Ok(());
+        }
+
+        let rows = match self.glue.execute("SELECT * FROM entries;").await {
+            Ok(mut res) => match res.remove(0) {
+                Payload::Select { labels, rows } => rows
+                    .into_iter()
+                    .map(|row| labels.iter().cloned().zip(row).collect::<HashMap<_, _>>())
+                    .collect::<Vec<_>>(),
+                _ => return Err(anyhow!("expected query to return rows")),
+            },
+            Err(err) => return Err(anyhow!(err)),
+        };
+
+        let mut queries = vec![
+            "DROP TABLE entries;".to_string(),
+            format!("CREATE TABLE entries ({});", column_defs()),
+        ];
+        for row in rows {
+            let values = ENTRIES_COLUMNS
+                .iter()
+                .map(|(name, _)| literal(row.get(*name).unwrap_or(&Value::Null)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            queries.push(format!(
+                "INSERT INTO entries VALUES ({});",
+                values.join(", ")
+            ));
+        }
+
+        match self.glue.execute(queries.join("\n")).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(anyhow!(err)),
+        }
+    }
+}
+
+fn column_defs() -> String {
+    ENTRIES_COLUMNS
+        .iter()
+        .map(|(name, def)| format!("{} {}", name, def))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Quotes a string for use as an SQL literal.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn literal(value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::Str(v) => Ok(quote(v)),
+        Value::U64(v) => Ok(v.to_string()),
+        Value::Null => Ok("NULL".into()),
+        v => Err(anyhow!("unexpected value {:?} in entries", v)),
+    }
 }
 
 #[derive(Debug)]
@@ -95,33 +198,161 @@ pub struct EntriesRow {
     pub path: String,
     pub hash: String,
     pub modified: u64,
+    /// The ETag returned by the remote when the object was uploaded
+    pub etag: Option<String>,
+    /// The SHA-256 checksum verified by the remote on upload, in the form S3 reports it
+    pub checksum: Option<String>,
+    /// The size of the object in bytes
+    pub size: Option<u64>,
+    /// The label of the host or disk the object was pushed from, if one was given
+    pub source: Option<String>,
+    /// The storage class the object was uploaded with, if one was chosen
+    pub storage_class: Option<String>,
+    /// How the object's content was compressed, `zstd` or `brotli`, if it was
+    pub codec: Option<String>,
+    /// The pack object holding the object's content, if it was packed with other small files
+    pub pack_key: Option<String>,
+    /// Where the content starts in the pack object
+    pub pack_offset: Option<u64>,
+    /// The length of the content in the pack object
+    pub pack_length: Option<u64>,
+}
+
+impl EntriesRow {
+    pub fn new(entry: &PlanEntry) -> anyhow::Result<Self> {
+        Ok(EntriesRow {
+            key: entry.key.clone(),
+            path: entry
+                .path
+                .to_str()
+                .ok_or_else(|| anyhow!("path {:?} is not valid UTF-8", entry.path))?
+                .to_string(),
+            hash: entry.hash.clone(),
+            modified: entry.modified,
+            etag: None,
+            checksum: None,
+            size: None,
+            source: None,
+            storage_class: entry.storage_class.clone(),
+            codec: entry.codec.clone(),
+            pack_key: None,
+            pack_offset: None,
+            pack_length: None,
+        })
+    }
+
+    /// Where the content is stored in its pack object, if it is packed.
+    pub fn pack(&self) -> Option<PackRange> {
+        match (&self.pack_key, self.pack_offset, self.pack_length) {
+            (Some(pack_key), Some(offset), Some(length)) => Some(PackRange {
+                pack_key: pack_key.clone(),
+                offset,
+                length,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The values of this row, in the order of `ENTRIES_COLUMNS`.
+    fn values(&self) -> Vec<Value> {
+        let optional = |v: &Option<String>| v.clone().map(Value::Str).unwrap_or(Value::Null);
+        vec![
+            Value::Str(self.key.clone()),
+            Value::Str(self.hash.clone()),
+            Value::Str(self.path.clone()),
+            Value::U64(self.modified),
+            optional(&self.etag),
+            optional(&self.checksum),
+            self.size.map(Value::U64).unwrap_or(Value::Null),
+            optional(&self.source),
+            optional(&self.storage_class),
+            optional(&self.codec),
+            optional(&self.pack_key),
+            self.pack_offset.map(Value::U64).unwrap_or(Value::Null),
+            self.pack_length.map(Value::U64).unwrap_or(Value::Null),
+        ]
+    }
 }
 
 impl TryFrom<HashMap<&str, &Value>> for EntriesRow {
     type Error = anyhow::Error;
 
     fn try_from(value: HashMap<&str, &Value>) -> Result<Self, Self::Error> {
-        let key = match *value.get("key").unwrap() {
-            Value::Str(v) => v.clone(),
+        let key = match value.get("key").copied() {
+            Some(Value::Str(v)) => v.clone(),
             _ => return Err(anyhow!("`key` expected to be Str")),
         };
-        let path = match *value.get("path").unwrap() {
-            Value::Str(v) => v.clone(),
+        let path = match value.get("path").copied() {
+            Some(Value::Str(v)) => v.clone(),
             _ => return Err(anyhow!("`path` expected to be Str")),
         };
-        let hash = match *value.get("hash").unwrap() {
-            Value::Str(v) => v.clone(),
+        let hash = match value.get("hash").copied() {
+            Some(Value::Str(v)) => v.clone(),
             _ => return Err(anyhow!("`hash` expected to be Str")),
         };
-        let modified = match *value.get("modified").unwrap() {
-            Value::U64(v) => *v,
+        let modified = match value.get("modified").copied() {
+            Some(Value::U64(v)) => *v,
             _ => return Err(anyhow!("`modified` expected to be u64")),
         };
+        let etag = match value.get("etag").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`etag` expected to be Str")),
+        };
+        let checksum = match value.get("checksum").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`checksum` expected to be Str")),
+        };
+        let size = match value.get("size").copied() {
+            Some(Value::U64(v)) => Some(*v),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`size` expected to be u64")),
+        };
+        let source = match value.get("source").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`source` expected to be Str")),
+        };
+        let storage_class = match value.get("storage_class").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`storage_class` expected to be Str")),
+        };
+        let codec = match value.get("codec").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`codec` expected to be Str")),
+        };
+        let pack_key = match value.get("pack_key").copied() {
+            Some(Value::Str(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`pack_key` expected to be Str")),
+        };
+        let pack_offset = match value.get("pack_offset").copied() {
+            Some(Value::U64(v)) => Some(*v),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`pack_offset` expected to be u64")),
+        };
+        let pack_length = match value.get("pack_length").copied() {
+            Some(Value::U64(v)) => Some(*v),
+            Some(Value::Null) | None => None,
+            _ => return Err(anyhow!("`pack_length` expected to be u64")),
+        };
         Ok(EntriesRow {
             key,
             path,
             hash,
             modified,
+            etag,
+            checksum,
+            size,
+            source,
+            storage_class,
+            codec,
+            pack_key,
+            pack_offset,
+            pack_length,
         })
     }
 }
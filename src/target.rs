@@ -0,0 +1,145 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use clap::{arg, ArgGroup, ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, Error};
+
+/// Where objects are stored; given as `--bucket <BUCKET>` for S3, or `--target <URL>` for any store,
+/// or by the name of a target in `s3b.toml`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Target {
+    /// An S3 bucket, `s3://<bucket>`
+    S3(String),
+    /// A Google Cloud Storage bucket, `gs://<bucket>`
+    Gcs(String),
+    /// An Azure Blob Storage container, `az://<container>`
+    Azure(String),
+    /// A local directory such as a NAS mount, `file:///<path>`
+    Local(PathBuf),
+    /// An in-memory store, `memory://`, which lasts only as long as the process; for tests
+    Memory,
+}
+
+impl Target {
+    /// Adds the `--bucket` and `--target` arguments. Without either, the target is taken from
+    /// `s3b.toml`.
+    pub fn args(command: Command) -> Command {
+        command
+            .arg(arg!(--"bucket" <BUCKET> "The name of an existing S3 bucket"))
+            .arg(
+                arg!(--"target" <URL> "Where to store objects, e.g. s3://my-bucket, gs://my-bucket, az://my-container or file:///mnt/backup, or the name of a target in s3b.toml")
+                    .value_parser(Target::check_arg),
+            )
+            .group(ArgGroup::new("destination").args(["bucket", "target"]))
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+        if let Some(bucket) = matches.get_one::<String>("bucket") {
+            return Ok(Target::S3(bucket.clone()));
+        }
+        if let Some(url) = matches
+            .get_one::<String>("target")
+            .filter(|t| t.contains("://"))
+        {
+            return Target::parse(url);
+        }
+        match Config::selected_target(matches)? {
+            Some(target) => target.target(),
+            None => Err(Error::Config(
+                "no target given; pass --bucket or --target, or set default_target in s3b.toml"
+                    .into(),
+            )
+            .into()),
+        }
+    }
+
+    /// Resolves a `--target` value, which is either a URL or the name of a target in `s3b.toml`.
+    pub fn resolve(value: &str) -> anyhow::Result<Self> {
+        if value.contains("://") {
+            Target::parse(value)
+        } else {
+            Config::get()?.target(value)?.target()
+        }
+    }
+
+    /// Checks that a `--target` value which looks like a URL is a valid one; names are looked up
+    /// once the command runs.
+    pub fn check_arg(value: &str) -> anyhow::Result<String> {
+        if value.contains("://") {
+            Target::parse(value)?;
+        }
+        Ok(value.to_string())
+    }
+
+    /// Parses a target URL such as `s3://my-bucket`, `gs://my-bucket`, `file:///mnt/backup` or `memory://`.
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        if let Some(bucket) = url.strip_prefix("s3://") {
+            Ok(Target::S3(bucket_name(url, bucket)?))
+        } else if let Some(bucket) = url.strip_prefix("gs://") {
+            Ok(Target::Gcs(bucket_name(url, bucket)?))
+        } else if let Some(container) = url.strip_prefix("az://") {
+            Ok(Target::Azure(bucket_name(url, container)?))
+        } else if let Some(path) = url.strip_prefix("file://") {
+            // the path must be absolute, so that a plan can be pushed from any directory
+            if !path.starts_with('/') {
+                return Err(Error::Config(format!(
+                    "invalid target '{}'; expected file:///<absolute path>",
+                    url
+                ))
+                .into());
+            }
+            Ok(Target::Local(PathBuf::from(path)))
+        } else if url == "memory://" {
+            Ok(Target::Memory)
+        } else {
+            Err(Error::Config(format!(
+                "unsupported target '{}'; expected s3://, gs://, az://, file:// or memory://",
+                url
+            ))
+            .into())
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::S3(bucket) => write!(f, "s3://{}", bucket),
+            Target::Gcs(bucket) => write!(f, "gs://{}", bucket),
+            Target::Azure(container) => write!(f, "az://{}", container),
+            Target::Local(path) => write!(f, "file://{}", path.display()),
+            Target::Memory => write!(f, "memory://"),
+        }
+    }
+}
+
+impl From<Target> for String {
+    fn from(target: Target) -> Self {
+        target.to_string()
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = anyhow::Error;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        Target::parse(&url)
+    }
+}
+
+/// Takes the bucket or container name from the rest of a target URL, which must not have a path.
+fn bucket_name(url: &str, rest: &str) -> anyhow::Result<String> {
+    let name = rest.trim_end_matches('/');
+    if name.is_empty() || name.contains('/') {
+        let scheme = &url[..url.len() - rest.len()];
+        return Err(Error::Config(format!(
+            "invalid target '{}'; expected {}<name> without a path",
+            url, scheme
+        ))
+        .into());
+    }
+    Ok(name.to_string())
+}
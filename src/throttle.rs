@@ -0,0 +1,289 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use chrono::{Local, NaiveTime};
+use clap::{arg, ArgMatches, Command};
+use http_body::{Body, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+
+use crate::{config::TargetConfig, parse_size, Error};
+
+/// The size of the chunks in which transfers are paced; smaller chunks give a smoother rate.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Limits the rate of bytes transferred to or from the bucket. Transfers are paced in chunks
+/// (see [`CHUNK_SIZE`]): downloads as they stream in, and uploads to S3 as their request bodies
+/// are sent (see [`PacedBody`]). Other targets take whole requests, so each one waits for its
+/// share of the rate before it is sent.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The rate in bytes per second outside of any scheduled window, or `None` for unlimited
+    rate: Option<u64>,
+    schedule: Vec<Window>,
+    /// The earliest time at which the next chunk may be sent
+    next: Mutex<Instant>,
+}
+
+/// A daily time window during which a different rate applies; windows may span midnight.
+#[derive(Debug, Clone)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    rate: Option<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>, schedule: Vec<Window>) -> Self {
+        Self {
+            rate,
+            schedule,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Adds the rate limit arguments to a transfer command.
+    pub fn args(command: Command) -> Command {
+        command
+            .arg(
+                arg!(--"limit-rate" <RATE> "Maximum transfer rate in bytes per second, with an optional K, M or G suffix")
+                    .value_parser(parse_size),
+            )
+            .arg(
+                arg!(--"limit-schedule" <WINDOWS> "Space-separated list of daily windows with their own rate, e.g. 08:00-23:00=1M")
+                    .value_parser(parse_window)
+                    .value_delimiter(' ')
+                    .num_args(1..),
+            )
+    }
+
+    /// Reads the rate limit arguments, falling back to the limits of the given configured target.
+    pub fn from_matches(
+        matches: &ArgMatches,
+        named: Option<&TargetConfig>,
+    ) -> anyhow::Result<Self> {
+        let config_err = |err: anyhow::Error| Error::Config(err.to_string());
+        let rate = match matches.get_one::<u64>("limit-rate") {
+            Some(rate) => Some(*rate),
+            None => named
+                .and_then(|target| target.limit_rate.as_deref())
+                .map(|rate| parse_size(rate).map_err(config_err))
+                .transpose()?,
+        };
+        let schedule = match matches.get_many::<Window>("limit-schedule") {
+            Some(windows) => windows.cloned().collect(),
+            None => named
+                .map(|target| target.limit_schedule.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|window| parse_window(window).map_err(config_err))
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self::new(rate, schedule))
+    }
+
+    /// Waits until `bytes` may be transferred without exceeding the current rate.
+    pub async fn acquire(&self, bytes: usize) {
+        if let Some(at) = self.reserve(bytes) {
+            tokio::time::sleep_until(at).await;
+        }
+    }
+
+    /// Reserves the next slot for `bytes`, returning when they may be sent, or `None` if the
+    /// current rate is unlimited.
+    fn reserve(&self, bytes: usize) -> Option<Instant> {
+        let rate = self.current_rate()?;
+        let mut next = self.next.lock().unwrap();
+        let at = std::cmp::max(*next, Instant::now());
+        *next = at + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        Some(at)
+    }
+
+    /// Whether any rate applies, now or at some time of the day.
+    pub fn is_limited(&self) -> bool {
+        self.rate.is_some() || self.schedule.iter().any(|window| window.rate.is_some())
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        let now = Local::now().time();
+        match self.schedule.iter().find(|w| w.contains(now)) {
+            Some(window) => window.rate,
+            None => self.rate,
+        }
+    }
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// A request body which is sent in chunks of at most [`CHUNK_SIZE`], each when the limiter allows,
+/// so that the rate holds while a request is being sent rather than only between requests.
+pub struct PacedBody {
+    data: Bytes,
+    limiter: Arc<RateLimiter>,
+    /// The wait before the next chunk, once it has been reserved
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl PacedBody {
+    pub fn new(data: impl Into<Bytes>, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            data: data.into(),
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+impl Body for PacedBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.data.is_empty() {
+            return Poll::Ready(None);
+        }
+        let len = std::cmp::min(CHUNK_SIZE, self.data.len());
+        if self.delay.is_none() {
+            let at = self.limiter.reserve(len).unwrap_or_else(Instant::now);
+            self.delay = Some(Box::pin(tokio::time::sleep_until(at)));
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        self.delay = None;
+        let chunk = self.data.split_to(len);
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.data.len() as u64)
+    }
+}
+
+/// Parses a window such as `08:00-23:00=1M`; the rate may also be `unlimited`.
+pub fn parse_window(window: &str) -> anyhow::Result<Window> {
+    let err = || anyhow!("invalid window '{}'; expected e.g. 08:00-23:00=1M", window);
+    let (times, rate) = window.split_once('=').ok_or_else(err)?;
+    let (start, end) = times.split_once('-').ok_or_else(err)?;
+    let start = NaiveTime::parse_from_str(start, "%H:%M").map_err(|_| err())?;
+    let end = NaiveTime::parse_from_str(end, "%H:%M").map_err(|_| err())?;
+    let rate = match rate {
+        "unlimited" => None,
+        rate => Some(parse_size(rate)?),
+    };
+    Ok(Window { start, end, rate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        RateLimiter::args(Command::new("push")).get_matches_from(args)
+    }
+
+    #[test]
+    fn limits_fall_back_to_the_target() {
+        let named: TargetConfig = toml::from_str(
+            r#"
+            limit_rate = "2M"
+            limit_schedule = ["08:00-23:00=1M", "23:00-06:00=unlimited"]
+            "#,
+        )
+        .unwrap();
+        let limiter = RateLimiter::from_matches(&matches(&["push"]), Some(&named)).unwrap();
+        assert_eq!(limiter.rate, Some(2 * 1024 * 1024));
+        assert_eq!(limiter.schedule.len(), 2);
+        assert_eq!(limiter.schedule[0].rate, Some(1024 * 1024));
+        assert_eq!(limiter.schedule[1].rate, None);
+
+        // the command line replaces either limit
+        let args = [
+            "push",
+            "--limit-rate",
+            "500K",
+            "--limit-schedule",
+            "01:00-02:00=1K",
+        ];
+        let limiter = RateLimiter::from_matches(&matches(&args), Some(&named)).unwrap();
+        assert_eq!(limiter.rate, Some(500 * 1024));
+        assert_eq!(limiter.schedule.len(), 1);
+
+        let limiter = RateLimiter::from_matches(&matches(&["push"]), None).unwrap();
+        assert_eq!(limiter.rate, None);
+        assert!(limiter.schedule.is_empty());
+    }
+
+    #[test]
+    fn invalid_limits_in_the_target_are_config_errors() {
+        for config in [r#"limit_rate = "fast""#, r#"limit_schedule = ["8-23=1M"]"#] {
+            let named: TargetConfig = toml::from_str(config).unwrap();
+            let err = RateLimiter::from_matches(&matches(&["push"]), Some(&named)).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::Config(_))
+            ));
+        }
+    }
+
+    async fn send(mut body: PacedBody) -> Vec<usize> {
+        let mut chunks = Vec::new();
+        while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await
+        {
+            chunks.push(frame.unwrap().into_data().unwrap().len());
+        }
+        assert!(body.is_end_stream());
+        chunks
+    }
+
+    #[tokio::test]
+    async fn bodies_are_paced_in_chunks() {
+        let rate = 8 * CHUNK_SIZE as u64;
+        let limiter = Arc::new(RateLimiter::new(Some(rate), Vec::new()));
+        let body = PacedBody::new(vec![0u8; 4 * CHUNK_SIZE + 1], limiter);
+        assert_eq!(body.size_hint().exact(), Some(4 * CHUNK_SIZE as u64 + 1));
+        let start = Instant::now();
+        let chunks = send(body).await;
+        assert_eq!(chunks, [CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE, 1]);
+        // the last chunk waits for the four before it, half a second at this rate
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        let limiter = Arc::new(RateLimiter::new(None, Vec::new()));
+        let start = Instant::now();
+        assert_eq!(send(PacedBody::new(vec![0u8; 3], limiter)).await, [3]);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn windows_may_span_midnight() {
+        let time = |time| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let day = parse_window("08:00-23:00=1M").unwrap();
+        assert!(day.contains(time("08:00")) && day.contains(time("22:59")));
+        assert!(!day.contains(time("23:00")) && !day.contains(time("07:59")));
+        let night = parse_window("23:00-06:00=unlimited").unwrap();
+        assert!(night.contains(time("23:30")) && night.contains(time("05:00")));
+        assert!(!night.contains(time("12:00")));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Target};
+
+const UPLOADS_FILE: &str = "s3b_uploads.bin";
+
+/// Tracks multipart uploads which are in progress, so that an interrupted `push` can continue
+/// from the last completed part. Persisted as `s3b_uploads.bin` in the directory of the plan file
+/// while a push is running, so that the plan can be pushed again from any directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UploadLog {
+    /// Keyed by target and object key, as `<target>/<key>`, since uploads can't be resumed elsewhere
+    uploads: HashMap<String, MultipartState>,
+    /// Where the log is persisted
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// The state of one multipart upload, keyed in [`UploadLog`] by target and object key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultipartState {
+    pub upload_id: String,
+    /// Size and modified time of the source file; a changed file restarts the upload
+    pub len: u64,
+    pub modified: u64,
+    pub part_size: u64,
+    /// Each part which has been uploaded, by part index
+    pub parts: Vec<Option<UploadedPart>>,
+    /// The storage class the upload was started with; a different class restarts the upload
+    pub storage_class: Option<String>,
+    /// The nonce of an encrypted upload, so that its remaining parts are encrypted to match
+    pub nonce: Option<[u8; 24]>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadedPart {
+    /// Identifies the part when completing the upload
+    pub content_id: String,
+    /// The SHA-256 digest of the part, which the remote verified on upload
+    pub sha256: Vec<u8>,
+}
+
+impl UploadLog {
+    /// Reads the upload log kept beside `plan_file`, or returns an empty log if there is none.
+    pub fn read(plan_file: &Path) -> anyhow::Result<Self> {
+        let path = plan_file.with_file_name(UPLOADS_FILE);
+        let mut log: Self = match std::fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes).map_err(|err| {
+                Error::Integrity(format!("could not decode {}: {}", path.display(), err))
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(err.into()),
+        };
+        log.path = path;
+        Ok(log)
+    }
+
+    /// Writes the upload log, removing the file once no uploads are in progress.
+    pub fn write(&self) -> anyhow::Result<()> {
+        if self.uploads.is_empty() {
+            match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        } else {
+            std::fs::write(&self.path, bincode::serialize(&self)?).map_err(|err| err.into())
+        }
+    }
+
+    pub fn get(&self, target: &Target, key: &str) -> Option<&MultipartState> {
+        self.uploads.get(&log_key(target, key))
+    }
+
+    pub fn get_mut(&mut self, target: &Target, key: &str) -> Option<&mut MultipartState> {
+        self.uploads.get_mut(&log_key(target, key))
+    }
+
+    pub fn insert(&mut self, target: &Target, key: &str, state: MultipartState) {
+        self.uploads.insert(log_key(target, key), state);
+    }
+
+    pub fn remove(&mut self, target: &Target, key: &str) -> Option<MultipartState> {
+        self.uploads.remove(&log_key(target, key))
+    }
+
+    pub fn contains_upload_id(&self, target: &Target, upload_id: &str) -> bool {
+        let prefix = log_key(target, "");
+        self.uploads
+            .iter()
+            .any(|(k, s)| k.starts_with(&prefix) && s.upload_id == upload_id)
+    }
+}
+
+fn log_key(target: &Target, key: &str) -> String {
+    format!("{}/{}", target, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_is_kept_beside_the_plan() {
+        let dir = std::env::temp_dir().join(format!("s3b-uploads-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plan_file = dir.join("laptop.plan");
+        let target = Target::Memory;
+        let state = MultipartState {
+            upload_id: "0000000001".into(),
+            len: 10,
+            modified: 0,
+            part_size: 5,
+            parts: vec![None, None],
+            storage_class: None,
+            nonce: None,
+        };
+
+        let mut log = UploadLog::read(&plan_file).unwrap();
+        log.insert(&target, "a.txt", state);
+        log.write().unwrap();
+        assert!(dir.join(UPLOADS_FILE).exists());
+
+        // read back through the plan's path, whatever the current directory
+        let mut log = UploadLog::read(&plan_file).unwrap();
+        assert!(log.contains_upload_id(&target, "0000000001"));
+        assert!(!log.contains_upload_id(&Target::S3("other".into()), "0000000001"));
+        log.remove(&target, "a.txt");
+        log.write().unwrap();
+        assert!(!dir.join(UPLOADS_FILE).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}